@@ -46,6 +46,10 @@ struct Args {
     #[arg(long, default_value_t = false, conflicts_with = "no_indexing")]
     no_build: bool,
 
+    /// Insert and build the items at once with the bulk loader. Only works on an empty database.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["no_indexing", "no_insert", "no_build"])]
+    bulk_load: bool,
+
     /// Don't commit after the operation, can be useful to benchmark only the build part of the indexing process
     /// without having to make the insertion again.
     #[arg(long, default_value_t = false, conflicts_with = "no_indexing")]
@@ -155,50 +159,69 @@ fn main() {
     if !args.no_indexing {
         let mut metadata_builder: BTreeMap<String, RoaringBitmap> = BTreeMap::new();
 
-        if !args.no_insert {
-            println!("Inserting points");
+        if args.bulk_load {
+            println!("Bulk loading the items...");
             let time = std::time::Instant::now();
-            let mut cpt = 0;
-            let mut prev_cpt = 0;
-
-            let mut print_timer = time;
-            for (name, geometry) in input {
-                let elapsed_since_last_print = print_timer.elapsed();
-                if elapsed_since_last_print > Duration::from_secs(1) {
-                    let elapsed = time.elapsed();
-                    let additional_points = cpt - prev_cpt;
-                    if cpt > 0 {
-                        print!("\x1b[A\x1b[2K");
-                    }
-                    println!(
-                        "Inserted {additional_points} additional points in {elapsed_since_last_print:.2?}, throughput: {:.2} points / seconds || In total: {cpt} points, started {:.2?} ago, throughput: {:.2} points / seconds",
-                        additional_points as f32 / elapsed_since_last_print.as_secs_f32(),
-                        time.elapsed(),
-                        cpt as f32 / elapsed.as_secs_f32()
-                    );
-                    print_timer = std::time::Instant::now();
-                    prev_cpt = cpt;
-                }
-                cpt += 1;
-                cellulite.add(&mut wtxn, cpt, &geometry).unwrap();
+            let progress = DefaultProgress::default();
+            progress.follow_progression_on_tty();
+            let items = input.enumerate().map(|(cpt, (name, geometry))| {
+                let cpt = cpt as u32 + 1;
                 if args.index_metadata {
                     metadata_builder.entry(name).or_default().insert(cpt);
                 }
-            }
-            let duration = time.elapsed();
-            println!(
-                "Inserted {cpt} points in {duration:.2?}. Throughput: {:.2} points / seconds",
-                cpt as f32 / duration.as_secs_f32()
-            );
-        }
-        if !args.no_build {
-            println!("Building the index...");
-            let progress = DefaultProgress::default();
-            progress.follow_progression_on_tty();
-            cellulite.build(&mut wtxn, &|| false, &progress).unwrap();
+                (cpt, geo::Geometry::try_from(geometry).unwrap())
+            });
+            cellulite
+                .bulk_load(&mut wtxn, items, &|| false, &progress)
+                .unwrap();
             progress.finish();
+            println!("Bulk loaded the items in {:?}", time.elapsed());
+        } else {
+            if !args.no_insert {
+                println!("Inserting points");
+                let time = std::time::Instant::now();
+                let mut cpt = 0;
+                let mut prev_cpt = 0;
+
+                let mut print_timer = time;
+                for (name, geometry) in input {
+                    let elapsed_since_last_print = print_timer.elapsed();
+                    if elapsed_since_last_print > Duration::from_secs(1) {
+                        let elapsed = time.elapsed();
+                        let additional_points = cpt - prev_cpt;
+                        if cpt > 0 {
+                            print!("\x1b[A\x1b[2K");
+                        }
+                        println!(
+                            "Inserted {additional_points} additional points in {elapsed_since_last_print:.2?}, throughput: {:.2} points / seconds || In total: {cpt} points, started {:.2?} ago, throughput: {:.2} points / seconds",
+                            additional_points as f32 / elapsed_since_last_print.as_secs_f32(),
+                            time.elapsed(),
+                            cpt as f32 / elapsed.as_secs_f32()
+                        );
+                        print_timer = std::time::Instant::now();
+                        prev_cpt = cpt;
+                    }
+                    cpt += 1;
+                    cellulite.add(&mut wtxn, cpt, &geometry).unwrap();
+                    if args.index_metadata {
+                        metadata_builder.entry(name).or_default().insert(cpt);
+                    }
+                }
+                let duration = time.elapsed();
+                println!(
+                    "Inserted {cpt} points in {duration:.2?}. Throughput: {:.2} points / seconds",
+                    cpt as f32 / duration.as_secs_f32()
+                );
+            }
+            if !args.no_build {
+                println!("Building the index...");
+                let progress = DefaultProgress::default();
+                progress.follow_progression_on_tty();
+                cellulite.build(&mut wtxn, &|| false, &progress).unwrap();
+                progress.finish();
 
-            println!("Index built in {:?}", time.elapsed());
+                println!("Index built in {:?}", time.elapsed());
+            }
         }
 
         // If the metadata should be indexed, we must build an fst containing
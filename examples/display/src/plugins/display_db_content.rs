@@ -109,7 +109,7 @@ impl Plugin for DisplayDbContent {
                             *cell,
                             Color32::BLUE.lerp_to_gamma(
                                 Color32::RED,
-                                bitmap.len() as f32 / self.runner.db.threshold as f32,
+                                bitmap.len() as f32 / self.runner.db.settings().threshold as f32,
                             ),
                         );
                     }
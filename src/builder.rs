@@ -1,17 +1,22 @@
-use std::{cell::RefCell, collections::HashMap, sync::atomic::Ordering};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::atomic::Ordering,
+};
 
 use crate::{
-    AtomicCellStep, AtomicItemStep, BuildSteps, ItemId, Result, keys::UpdateType,
-    metadata::Version, pos,
+    AtomicCellStep, AtomicItemStep, BuildSteps, ItemId, Result,
+    keys::{Update, UpdateType},
+    metadata::FORMAT_VERSION,
+    pos,
 };
 use geo::MultiPolygon;
 use h3o::{
     CellIndex, LatLng, Resolution,
     geom::{ContainmentMode, PlotterBuilder, TilerBuilder},
 };
-use heed::{RoTxn, RwTxn};
-use intmap::IntMap;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use heed::{RoTxn, RwTxn, types::DecodeIgnore};
+use rayon::iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
 use roaring::RoaringBitmap;
 use steppe::Progress;
 use thread_local::ThreadLocal;
@@ -19,21 +24,33 @@ use zerometry::{InputRelation, RelationBetweenShapes, Zerometry};
 
 use crate::{Cellulite, Error, keys::Key};
 
+/// The level-zero cells and bellies the overwritten items were indexed in, with the items to remove from them.
+#[derive(Default)]
+struct PreviousCells {
+    cells: HashMap<CellIndex, RoaringBitmap>,
+    bellies: HashMap<CellIndex, RoaringBitmap>,
+}
+
 impl Cellulite {
-    fn retrieve_frozen_items<'a>(
+    /// Retrieve the shapes of the items directly from the items database.
+    fn retrieve_shapes<'a>(
         &self,
         rtxn: &'a RoTxn,
-        cancel: impl Fn() -> bool + Send + Sync,
-    ) -> Result<FrozenItems<'a>> {
-        let mut items = IntMap::with_capacity(self.item.len(rtxn)? as usize);
-        for ret in self.item.iter(rtxn)? {
+        cancel: &(impl Fn() -> bool + Send + Sync),
+        items: &RoaringBitmap,
+    ) -> Result<Vec<(ItemId, Zerometry<'a>)>> {
+        let mut shapes = Vec::with_capacity(items.len() as usize);
+        for item in items.iter() {
             if cancel() {
                 return Err(Error::BuildCanceled);
             }
-            let (k, v) = ret?;
-            items.insert(k, v);
+            let shape = self
+                .item_db()
+                .get(rtxn, &item)?
+                .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
+            shapes.push((item, shape));
         }
-        Ok(FrozenItems { items })
+        Ok(shapes)
     }
 
     fn retrieve_and_clear_updated_items(
@@ -41,74 +58,77 @@ impl Cellulite {
         wtxn: &mut RwTxn,
         cancel: impl Fn() -> bool + Send + Sync,
         progress: &impl Progress,
-    ) -> Result<(RoaringBitmap, RoaringBitmap)> {
+    ) -> Result<(RoaringBitmap, RoaringBitmap, PreviousCells)> {
         progress.update(BuildSteps::RetrieveUpdatedItems);
         let (atomic, step) = AtomicItemStep::new(self.update.len(wtxn)?);
         progress.update(step);
 
         let mut inserted = RoaringBitmap::new();
         let mut deleted = RoaringBitmap::new();
+        let mut previous = PreviousCells::default();
 
         for ret in self.update.iter(wtxn)? {
             if cancel() {
                 return Err(Error::BuildCanceled);
             }
-            match ret? {
-                (item, UpdateType::Insert) => inserted.try_push(item).unwrap(),
-                (item, UpdateType::Delete) => deleted.try_push(item).unwrap(),
+            let (item, update) = ret?;
+            match update.kind {
+                UpdateType::Insert => inserted.try_push(item).unwrap(),
+                UpdateType::Delete => deleted.try_push(item).unwrap(),
+            }
+            for cell in update.previous_cells {
+                previous.cells.entry(cell).or_default().insert(item);
+            }
+            for cell in update.previous_bellies {
+                previous.bellies.entry(cell).or_default().insert(item);
             }
             atomic.fetch_add(1, Ordering::Relaxed);
         }
         progress.update(BuildSteps::ClearUpdatedItems);
         self.update.clear(wtxn)?;
 
-        Ok((inserted, deleted))
+        Ok((inserted, deleted, previous))
     }
 
     /// Build all the internal structure required to query the database.
     // Indexing is in 4 steps:
     // 1. We retrieve all the items that have been updated since the last indexing
     // 2. We remove the deleted items from the database and remove the empty cells at the same time
-    //    TODO: If a cell becomes too small we cannot delete it so the database won't shrink properly but won't be corrupted either
+    //    The overwritten items are removed from the cells of their previous shape as well
+    //    If a cell becomes too small its children are kept, they can be removed with `Self::compact`
     // 3. We insert the new items in the database **only at the level 0**
     // 4. We take each level-zero cell one by one and if it contains new items we insert them in the database in batch at the next level
-    //    TODO: Could be parallelized fairly easily I think
+    //    The relations between the items and the cells are computed in parallel, then written serially in the database
     pub fn build(
         &self,
         wtxn: &mut RwTxn,
         cancel: &(impl Fn() -> bool + Send + Sync),
         progress: &impl Progress,
     ) -> Result<()> {
-        let db_version = self.get_version(wtxn)?;
-        if db_version != Version::default() {
-            return Err(Error::VersionMismatchOnBuild(db_version));
+        let db_format = self.get_format_version(wtxn)?;
+        if db_format != FORMAT_VERSION {
+            return Err(Error::VersionMismatchOnBuild(db_format));
         }
 
         // 1.
-        let (inserted_items, removed_items) =
+        let (inserted_items, removed_items, previous_cells) =
             self.retrieve_and_clear_updated_items(wtxn, cancel, progress)?;
         if inserted_items.is_empty() && removed_items.is_empty() {
-            self.set_version(wtxn, &Version::default())?;
+            self.update_metadata(wtxn)?;
             return Ok(());
         }
 
         // 2.
-        self.remove_deleted_items(wtxn, cancel, progress, removed_items)?;
+        self.remove_deleted_items(wtxn, cancel, progress, removed_items, previous_cells)?;
         if inserted_items.is_empty() {
-            self.set_version(wtxn, &Version::default())?;
+            self.update_metadata(wtxn)?;
             return Ok(());
         }
 
-        // 3.0
-        let frozen_items = self.retrieve_frozen_items(wtxn, cancel)?;
-        // currently heed doesn't know that writing in a database doesn't invalidate the pointers in another
-        let frozen_items: FrozenItems<'static> = unsafe { std::mem::transmute(frozen_items) };
-
-        // 3.1
-        self.insert_items_at_level_zero(wtxn, cancel, progress, &inserted_items, &frozen_items)?;
+        // 3.
+        self.insert_items_at_level_zero(wtxn, cancel, progress, &inserted_items)?;
 
         // 4. We have to iterate over all the level-zero cells and insert the new items that are in them in the database at the next level if we need to
-        //    TODO: Could be parallelized
         progress.update(BuildSteps::InsertItemsRecursively); // we cannot detail more here
         for cell in CellIndex::base_cells() {
             if cancel() {
@@ -118,36 +138,141 @@ impl Cellulite {
                 .cell_db()
                 .get(wtxn, &Key::Cell(cell))?
                 .unwrap_or_default();
+            let new_items = &bitmap & &inserted_items;
             // Awesome, we don't care about what's in the cell, wether it have multiple levels or not
-            if bitmap.len() < self.threshold || bitmap.intersection_len(&inserted_items) == 0 {
+            if !self.settings.must_split(bitmap.len(), cell.resolution()) || new_items.is_empty() {
                 continue;
             }
-            self.insert_chunk_of_items_recursively(
-                wtxn,
-                cancel,
-                inserted_items.clone(),
-                bitmap,
-                cell,
-                &frozen_items,
-            )?;
+            // If the cell was already split only the new items must be inserted in its children
+            // otherwise it just became too large and all its items must go down
+            let items_to_insert = if self
+                .settings
+                .must_split(bitmap.len() - new_items.len(), cell.resolution())
+            {
+                new_items
+            } else {
+                bitmap.clone()
+            };
+            self.insert_chunk_of_items_recursively(wtxn, cancel, bitmap, items_to_insert, cell)?;
         }
 
         progress.update(BuildSteps::UpdateTheMetadata);
-        self.set_version(wtxn, &Version::default())?;
+        self.update_metadata(wtxn)?;
+
+        Ok(())
+    }
+
+    /// Remove the cells that cannot be reached anymore by the queries.
+    /// After deleting items, a cell can go back under the threshold. Its children won't be used anymore
+    /// since the cell contains all their items, but they're still stored in the database.
+    /// This function removes them along with their belly cells.
+    pub fn compact(
+        &self,
+        wtxn: &mut RwTxn,
+        cancel: &(impl Fn() -> bool + Send + Sync),
+        progress: &impl Progress,
+    ) -> Result<()> {
+        let db_format = self.get_format_version(wtxn)?;
+        if db_format != FORMAT_VERSION {
+            return Err(Error::VersionMismatchOnBuild(db_format));
+        }
+        steppe::make_enum_progress! {
+            pub enum CompactSteps {
+                RetrieveCells,
+                ComputeReachableCells,
+                RemoveUnreachableCells,
+            }
+        }
+
+        progress.update(CompactSteps::RetrieveCells);
+        let (atomic, step) = AtomicCellStep::new(self.cell_db().len(wtxn)?);
+        progress.update(step);
+        let mut cells_len = HashMap::new();
+        for ret in self.cell_db().iter(wtxn)? {
+            if cancel() {
+                return Err(Error::BuildCanceled);
+            }
+            if let (Key::Cell(cell), bitmap) = ret? {
+                cells_len.insert(cell, bitmap.len());
+            }
+            atomic.fetch_add(1, Ordering::Relaxed);
+        }
+
+        progress.update(CompactSteps::ComputeReachableCells);
+        let reachable = self.reachable_cells(&cells_len, cancel)?;
+
+        progress.update(CompactSteps::RemoveUnreachableCells);
+        let (atomic, step) = AtomicCellStep::new(self.cell_db().len(wtxn)?);
+        progress.update(step);
+        let mut iter = self
+            .cell_db()
+            .remap_data_type::<DecodeIgnore>()
+            .iter_mut(wtxn)?;
+        while let Some(ret) = iter.next() {
+            if cancel() {
+                return Err(Error::BuildCanceled);
+            }
+            let (key, ()) = ret?;
+            let (Key::Cell(cell) | Key::Belly(cell)) = key;
+            if !reachable.contains(&cell) {
+                // safe because we don't keep any reference to the database
+                unsafe { iter.del_current()? };
+            }
+            atomic.fetch_add(1, Ordering::Relaxed);
+        }
 
         Ok(())
     }
 
-    /// 1. We remove all the items by id of the items database
-    /// 2. We do a scan of the whole cell database and remove the items from the bitmaps
-    ///
-    /// TODO: We could optimize 2 and 3 by diving into the cells and stopping early when one is empty
+    /// Return the cells that can be reached by the queries from the length of all the cells in the database.
+    // A cell is reachable if it's a level-zero cell or the child of a reachable cell that was split.
+    // Since the children of neighbouring cells overlaps we can only know if a cell is unreachable once
+    // we've looked at all its potential parents, thus we compute the reachable cells resolution by resolution.
+    pub(crate) fn reachable_cells(
+        &self,
+        cells_len: &HashMap<CellIndex, u64>,
+        cancel: &impl Fn() -> bool,
+    ) -> Result<HashSet<CellIndex>> {
+        let mut reachable: HashSet<CellIndex> = CellIndex::base_cells().collect();
+        let mut to_explore: Vec<CellIndex> = CellIndex::base_cells()
+            .filter(|cell| cells_len.contains_key(cell))
+            .collect();
+        while !to_explore.is_empty() {
+            if cancel() {
+                return Err(Error::BuildCanceled);
+            }
+            let mut next_resolution = Vec::new();
+            for cell in to_explore {
+                if !self
+                    .settings
+                    .must_split(cells_len[&cell], cell.resolution())
+                {
+                    continue;
+                }
+                let Some(children) = get_children_cells(cell)? else {
+                    continue;
+                };
+                for child in children {
+                    if reachable.insert(child) && cells_len.contains_key(&child) {
+                        next_resolution.push(child);
+                    }
+                }
+            }
+            to_explore = next_resolution;
+        }
+        Ok(reachable)
+    }
+
+    /// 1. We retrieve the level-zero cells of the items from their shape and remove them from the items database
+    /// 2. We dive into these cells and the previous cells of the overwritten items and remove the items from the bitmaps,
+    ///    stopping as soon as a cell doesn't contain any of them
     fn remove_deleted_items(
         &self,
         wtxn: &mut RwTxn,
         cancel: impl Fn() -> bool + Send + Sync,
         progress: &impl Progress,
         items: RoaringBitmap,
+        previous: PreviousCells,
     ) -> Result<()> {
         progress.update(BuildSteps::RemoveDeletedItemsFromDatabase);
         steppe::make_enum_progress! {
@@ -160,48 +285,141 @@ impl Cellulite {
         progress.update(RemoveDeletedItemsSteps::RemoveDeletedItemsFromItemsDatabase);
         let (atomic, step) = AtomicItemStep::new(items.len());
         progress.update(step.clone());
+        let PreviousCells {
+            mut cells,
+            mut bellies,
+        } = previous;
+        let mut cells_vec = Vec::new();
+        let mut belly_vec = Vec::new();
         for item in items.iter() {
             if cancel() {
                 return Err(Error::BuildCanceled);
             }
+            cells_vec.clear();
+            belly_vec.clear();
+            // The item may have been deleted without ever being inserted
+            if let Some(shape) = self.item_db().get(wtxn, &item)? {
+                Self::explode_level_zero_geo(item, shape, &mut cells_vec, &mut belly_vec)?;
+            }
+            for cell in cells_vec.iter() {
+                cells.entry(*cell).or_default().insert(item);
+            }
+            for cell in belly_vec.iter() {
+                bellies.entry(*cell).or_default().insert(item);
+            }
             self.item_db().delete(wtxn, &item)?;
+            self.remove_properties(wtxn, item)?;
             atomic.fetch_add(1, Ordering::Relaxed);
         }
 
         progress.update(RemoveDeletedItemsSteps::RemoveDeletedItemsFromCellsDatabase);
-        let (atomic, step) = AtomicCellStep::new(self.cell_db().len(wtxn)?);
+        let (atomic, step) = AtomicCellStep::new(cells.len() as u64 + bellies.len() as u64);
         progress.update(step.clone());
-        let mut iter = self.cell_db().iter_mut(wtxn)?;
-        while let Some(ret) = iter.next() {
+        for (cell, items) in bellies {
             if cancel() {
                 return Err(Error::BuildCanceled);
             }
-            let (key, mut bitmap) = ret?;
-            let len = bitmap.len();
-            bitmap -= &items;
-            let removed = len - bitmap.len();
-            atomic.fetch_add(removed, Ordering::Relaxed);
-
-            // safe because everything is owned
-            unsafe {
-                if bitmap.is_empty() {
-                    iter.del_current()?;
-                } else {
-                    iter.put_current(&key, &bitmap)?;
+            self.remove_items_from_key(wtxn, Key::Belly(cell), &items)?;
+            atomic.fetch_add(1, Ordering::Relaxed);
+        }
+        for (cell, items) in cells {
+            self.remove_items_from_cell_recursively(wtxn, &cancel, cell, &items)?;
+            atomic.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Return the update to store when the shape of an item is overwritten.
+    /// If the item was already indexed, the level-zero cells of its current shape are kept in the update
+    /// since they cannot be retrieved anymore once the shape is overwritten. The build removes the item from them.
+    pub(crate) fn insert_update(&self, rtxn: &RoTxn, item: ItemId) -> Result<Update> {
+        let update = match self.update.get(rtxn, &item)? {
+            // If the item is pending insertion its current shape was never indexed, and if it was
+            // already overwritten its previous cells are still the ones of the indexed shape
+            Some(update)
+                if update.kind == UpdateType::Insert
+                    || !update.previous_cells.is_empty()
+                    || !update.previous_bellies.is_empty() =>
+            {
+                update
+            }
+            _ => {
+                let mut update = Update::new(UpdateType::Insert);
+                if let Some(shape) = self.item_db().get(rtxn, &item)? {
+                    Self::explode_level_zero_geo(
+                        item,
+                        shape,
+                        &mut update.previous_cells,
+                        &mut update.previous_bellies,
+                    )?;
                 }
+                update
             }
+        };
+        Ok(Update {
+            kind: UpdateType::Insert,
+            ..update
+        })
+    }
+
+    /// Remove the items from the cell and its children.
+    /// Since a child can only contain the items of its parents we only dive in the children
+    /// for the items that were in the cell.
+    fn remove_items_from_cell_recursively(
+        &self,
+        wtxn: &mut RwTxn,
+        cancel: &(impl Fn() -> bool + Send + Sync),
+        cell: CellIndex,
+        items: &RoaringBitmap,
+    ) -> Result<()> {
+        if cancel() {
+            return Err(Error::BuildCanceled);
+        }
+        let removed = self.remove_items_from_key(wtxn, Key::Cell(cell), items)?;
+        if removed.is_empty() {
+            return Ok(());
+        }
+        let Some(children) = get_children_cells(cell)? else {
+            return Ok(());
+        };
+        // Even if the cell is too small to be split its children may still exist from before it shrunk
+        for child in children {
+            self.remove_items_from_key(wtxn, Key::Belly(child), &removed)?;
+            self.remove_items_from_cell_recursively(wtxn, cancel, child, &removed)?;
         }
-        drop(iter);
         Ok(())
     }
 
+    /// Remove the items from the bitmap stored at `key` and delete it if it becomes empty.
+    /// Return the items that were actually removed.
+    fn remove_items_from_key(
+        &self,
+        wtxn: &mut RwTxn,
+        key: Key,
+        items: &RoaringBitmap,
+    ) -> Result<RoaringBitmap> {
+        let Some(mut bitmap) = self.cell_db().get(wtxn, &key)? else {
+            return Ok(RoaringBitmap::new());
+        };
+        let removed = &bitmap & items;
+        if removed.is_empty() {
+            return Ok(removed);
+        }
+        bitmap -= &removed;
+        if bitmap.is_empty() {
+            self.cell_db().delete(wtxn, &key)?;
+        } else {
+            self.cell_db().put(wtxn, &key, &bitmap)?;
+        }
+        Ok(removed)
+    }
+
     fn insert_items_at_level_zero(
         &self,
         wtxn: &mut RwTxn,
         cancel: impl Fn() -> bool + Send + Sync,
         progress: &impl Progress,
         items: &RoaringBitmap,
-        frozen_items: &FrozenItems<'static>,
     ) -> Result<()> {
         progress.update(BuildSteps::InsertItemsAtLevelZero);
         steppe::make_enum_progress! {
@@ -215,13 +433,14 @@ impl Cellulite {
         let (atomic, step) = AtomicItemStep::new(items.len());
         progress.update(step);
 
+        // The transaction cannot be shared between threads, we must retrieve the shapes beforehand
+        let shapes = self.retrieve_shapes(wtxn, &cancel, items)?;
         let tls_maps: ThreadLocal<RefCell<(HashMap<_, _>, HashMap<_, _>)>> = ThreadLocal::new();
         let tls_vecs: ThreadLocal<RefCell<(Vec<_>, Vec<_>)>> = ThreadLocal::new();
 
-        items
-            .iter()
-            .par_bridge()
-            .try_for_each(|item| -> Result<_> {
+        shapes
+            .par_iter()
+            .try_for_each(|&(item, shape)| -> Result<_> {
                 if cancel() {
                     return Err(Error::BuildCanceled);
                 }
@@ -230,9 +449,6 @@ impl Cellulite {
                 cells_vec.clear();
                 belly_vec.clear();
 
-                let shape = frozen_items
-                    .get(item)
-                    .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
                 Self::explode_level_zero_geo(item, shape, cells_vec, belly_vec)?;
                 for cell in cells_vec {
                     cells_map
@@ -266,6 +482,8 @@ impl Cellulite {
                     (l_insert, l_belly)
                 },
             );
+        // The shapes borrow the transaction, we must release them before writing
+        drop(shapes);
         progress.update(InsertItemsAtLevelZeroSteps::WriteCellsToDatabase);
         let (atomic, step) = AtomicCellStep::new(to_insert.len() as u64 + belly.len() as u64);
         progress.update(step);
@@ -297,7 +515,7 @@ impl Cellulite {
         Ok(())
     }
 
-    fn explode_level_zero_geo(
+    pub(crate) fn explode_level_zero_geo(
         // only used for error handling
         item: ItemId,
         shape: Zerometry,
@@ -307,14 +525,14 @@ impl Cellulite {
         match shape {
             Zerometry::Point(point) => {
                 let cell = LatLng::new(point.lat(), point.lng())
-                    .unwrap()
+                    .map_err(|err| Error::InvalidLatLng(item, err))?
                     .to_cell(Resolution::Zero);
                 cells.push(cell);
             }
             Zerometry::MultiPoints(multi_point) => {
                 for point in multi_point.points() {
                     let cell = LatLng::new(point.lat(), point.lng())
-                        .unwrap()
+                        .map_err(|err| Error::InvalidLatLng(item, err))?
                         .to_cell(Resolution::Zero);
                     cells.push(cell);
                 }
@@ -343,7 +561,9 @@ impl Cellulite {
             }
             Zerometry::Line(line) => {
                 let mut plotter = PlotterBuilder::new(Resolution::Zero).build();
-                plotter.add_batch(line.to_geo().lines()).unwrap();
+                plotter.add_batch(line.to_geo().lines()).map_err(|err| {
+                    Error::CannotConvertLineToCell(item, err, format!("{line:?}"))
+                })?;
 
                 for cell in plotter.plot() {
                     let ret_cells = cell.map_err(|err| {
@@ -356,7 +576,9 @@ impl Cellulite {
             Zerometry::MultiLines(multi_lines) => {
                 let mut plotter = PlotterBuilder::new(Resolution::Zero).build();
                 for line in multi_lines.lines() {
-                    plotter.add_batch(line.to_geo().lines()).unwrap();
+                    plotter.add_batch(line.to_geo().lines()).map_err(|err| {
+                        Error::CannotConvertLineToCell(item, err, format!("{multi_lines:?}"))
+                    })?;
                 }
 
                 for cell in plotter.plot() {
@@ -412,46 +634,14 @@ impl Cellulite {
         items_in_current_cell: RoaringBitmap,
         items_to_insert: RoaringBitmap,
         parent_cell: CellIndex,
-        frozen_items: &FrozenItems<'static>,
     ) -> Result<()> {
         // 1. If we cannot increase the resolution, we are done
         let Some(children_cells) = get_children_cells(parent_cell)? else {
             return Ok(());
         };
         // 2.
-        let mut to_insert = HashMap::with_capacity(children_cells.len());
-        let mut to_insert_in_belly = HashMap::new();
-
-        for &child_cell in children_cells.iter() {
-            if cancel() {
-                return Err(Error::BuildCanceled);
-            }
-            let cell_shape = get_cell_shape(child_cell);
-            for item in items_to_insert.iter() {
-                let shape = frozen_items
-                    .get(item)
-                    .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
-                let relation = shape.relation(
-                    &cell_shape,
-                    InputRelation {
-                        // we don't need to know if we're being strictly contained or not
-                        strict_contained: false,
-                        ..InputRelation::all()
-                    },
-                );
-                if relation.strict_contains.unwrap_or_default() {
-                    let entry = to_insert_in_belly
-                        .entry(child_cell)
-                        .or_insert_with(RoaringBitmap::new);
-                    entry.insert(item);
-                } else if relation.any_relation() {
-                    let entry = to_insert
-                        .entry(child_cell)
-                        .or_insert_with(RoaringBitmap::new);
-                    entry.insert(item);
-                }
-            }
-        }
+        let (to_insert, to_insert_in_belly) =
+            self.dispatch_items_in_cells(wtxn, cancel, &items_to_insert, &children_cells)?;
 
         // 3.
         for (cell, items) in to_insert_in_belly {
@@ -475,7 +665,9 @@ impl Cellulite {
                 original_bitmap.as_ref().unwrap_or(&Default::default()) | &items_to_insert;
             self.cell_db().put(wtxn, &Key::Cell(cell), &new_bitmap)?;
             if let Some(ref original_bitmap) = original_bitmap
-                && original_bitmap.len() >= self.threshold
+                && self
+                    .settings
+                    .must_split(original_bitmap.len(), cell.resolution())
             {
                 // if we were already too large we can immediately jump to the next resolution
                 self.insert_chunk_of_items_recursively(
@@ -484,34 +676,21 @@ impl Cellulite {
                     original_bitmap.clone(),
                     items_to_insert,
                     cell,
-                    frozen_items,
                 )?;
-            } else if new_bitmap.len() >= self.threshold {
-                let cell_shape = get_cell_shape(cell);
-                let mut belly_items = RoaringBitmap::new();
+            } else if self
+                .settings
+                .must_split(new_bitmap.len(), cell.resolution())
+            {
                 let original_bitmap =
                     original_bitmap.unwrap_or_else(|| items_in_current_cell.clone());
 
                 // If we just became too large, we have to retrieve the items that were already in the database insert them at the next resolution
-                for item_id in original_bitmap.iter() {
-                    let shape = frozen_items
-                        .get(item_id)
-                        .ok_or_else(|| Error::InternalDocIdMissing(item_id, pos!()))?;
-
-                    let relation = shape.relation(
-                        &cell_shape,
-                        InputRelation {
-                            // we don't need to know if we're being strictly contained or not
-                            strict_contained: false,
-                            ..InputRelation::all()
-                        },
-                    );
-                    if relation.strict_contains.unwrap_or_default() {
-                        belly_items.insert(item_id);
-                    } else if relation.any_relation() {
-                        items_to_insert.insert(item_id);
-                    }
+                let (mut cells, mut bellies) =
+                    self.dispatch_items_in_cells(wtxn, cancel, &original_bitmap, &[cell])?;
+                if let Some(items) = cells.remove(&cell) {
+                    items_to_insert |= items;
                 }
+                let belly_items = bellies.remove(&cell).unwrap_or_default();
 
                 let mut belly_cells = self
                     .cell_db()
@@ -526,13 +705,78 @@ impl Cellulite {
                     RoaringBitmap::new(),
                     items_to_insert,
                     cell,
-                    frozen_items,
                 )?;
             }
             // If we are not too large, we have nothing else to do yaay
         }
         Ok(())
     }
+
+    /// Find the cells each item intersects.
+    /// Returns the items to insert in each cell and the items to insert in the belly of each cell.
+    /// It only reads the items database, the cells must be written afterward.
+    #[allow(clippy::type_complexity)]
+    fn dispatch_items_in_cells(
+        &self,
+        rtxn: &RoTxn,
+        cancel: &(impl Fn() -> bool + Send + Sync),
+        items: &RoaringBitmap,
+        cells: &[CellIndex],
+    ) -> Result<(
+        HashMap<CellIndex, RoaringBitmap>,
+        HashMap<CellIndex, RoaringBitmap>,
+    )> {
+        let shapes = self.retrieve_shapes(rtxn, cancel, items)?;
+
+        // The relations are the expensive part and don't need the transaction, we can compute them in parallel
+        let relations = cells
+            .par_iter()
+            .map(|&cell| -> Result<_> {
+                if cancel() {
+                    return Err(Error::BuildCanceled);
+                }
+                let cell_shape = get_cell_shape(cell);
+                let (cell_items, belly_items) = shapes
+                    .par_iter()
+                    .fold(
+                        || (RoaringBitmap::new(), RoaringBitmap::new()),
+                        |(mut cell_items, mut belly_items), &(item, shape)| {
+                            let relation = shape.relation(
+                                &cell_shape,
+                                InputRelation {
+                                    // we don't need to know if we're being strictly contained or not
+                                    strict_contained: false,
+                                    ..InputRelation::all()
+                                },
+                            );
+                            if relation.strict_contains.unwrap_or_default() {
+                                belly_items.insert(item);
+                            } else if relation.any_relation() {
+                                cell_items.insert(item);
+                            }
+                            (cell_items, belly_items)
+                        },
+                    )
+                    .reduce(
+                        || (RoaringBitmap::new(), RoaringBitmap::new()),
+                        |(l_cell, l_belly), (r_cell, r_belly)| (l_cell | r_cell, l_belly | r_belly),
+                    );
+                Ok((cell, cell_items, belly_items))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut to_insert = HashMap::with_capacity(cells.len());
+        let mut to_insert_in_belly = HashMap::new();
+        for (cell, cell_items, belly_items) in relations {
+            if !cell_items.is_empty() {
+                to_insert.insert(cell, cell_items);
+            }
+            if !belly_items.is_empty() {
+                to_insert_in_belly.insert(cell, belly_items);
+            }
+        }
+        Ok((to_insert, to_insert_in_belly))
+    }
 }
 
 fn get_cell_shape(cell: CellIndex) -> MultiPolygon {
@@ -542,7 +786,7 @@ fn get_cell_shape(cell: CellIndex) -> MultiPolygon {
 /// Return None if we cannot increase the resolution
 /// Otherwise, return the children cells in a very non-efficient way
 /// Note: We cannot use the `get_children_cells` function because it doesn't return the full coverage of our cells and leaves holes
-fn get_children_cells(cell: CellIndex) -> Result<Option<Vec<CellIndex>>, Error> {
+pub(crate) fn get_children_cells(cell: CellIndex) -> Result<Option<Vec<CellIndex>>, Error> {
     let Some(next_res) = cell.resolution().succ() else {
         return Ok(None);
     };
@@ -550,13 +794,3 @@ fn get_children_cells(cell: CellIndex) -> Result<Option<Vec<CellIndex>>, Error>
     let center_child = cell.center_child(next_res).unwrap();
     Ok(Some(center_child.grid_disk(2)))
 }
-
-struct FrozenItems<'a> {
-    items: IntMap<ItemId, Zerometry<'a>>,
-}
-
-impl<'a> FrozenItems<'a> {
-    pub fn get(&self, item: u32) -> Option<Zerometry<'a>> {
-        self.items.get(item).copied()
-    }
-}
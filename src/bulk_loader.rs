@@ -0,0 +1,233 @@
+use std::{collections::HashMap, sync::atomic::Ordering};
+
+use geo::{Geometry, MultiPolygon};
+use h3o::CellIndex;
+use heed::{PutFlags, RwTxn};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use roaring::RoaringBitmap;
+use steppe::Progress;
+use zerometry::{InputRelation, RelationBetweenShapes, Zerometry};
+
+use crate::{
+    AtomicCellStep, AtomicItemStep, Cellulite, Error, ItemId, Result,
+    builder::get_children_cells,
+    keys::{Key, KeyVariant},
+    metadata::FORMAT_VERSION,
+    pos, validate_coordinates,
+};
+
+type CellsMap = HashMap<CellIndex, RoaringBitmap>;
+
+steppe::make_enum_progress! {
+    pub enum BulkLoadSteps {
+        SortItems,
+        WriteItems,
+        InsertItemsAtLevelZero,
+        InsertItemsRecursively,
+        WriteCells,
+    }
+}
+
+impl Cellulite {
+    /// Fill an empty database with all the items at once.
+    /// Unlike calling [`Self::add`] for every item and then [`Self::build`], the items are written only once,
+    /// the cells are computed in memory and written in their order in the database.
+    /// If the same item id is present multiple times, the last geometry is kept.
+    pub fn bulk_load(
+        &self,
+        wtxn: &mut RwTxn,
+        items: impl IntoIterator<Item = (ItemId, Geometry)>,
+        cancel: &(impl Fn() -> bool + Send + Sync),
+        progress: &impl Progress,
+    ) -> Result<()> {
+        let db_format = self.get_format_version(wtxn)?;
+        if db_format != FORMAT_VERSION {
+            return Err(Error::VersionMismatchOnBuild(db_format));
+        }
+        if !self.item.is_empty(wtxn)?
+            || !self.cell.is_empty(wtxn)?
+            || !self.update.is_empty(wtxn)?
+        {
+            return Err(Error::BulkLoadOnNonEmptyDatabase);
+        }
+
+        progress.update(BulkLoadSteps::SortItems);
+        let mut items = items
+            .into_iter()
+            .map(|(item, geometry)| {
+                if cancel() {
+                    return Err(Error::BuildCanceled);
+                }
+                validate_coordinates(item, &geometry)?;
+                Ok((item, geometry))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // The sort is stable, by swapping the duplicates we keep the last one
+        items.sort_by_key(|(item, _)| *item);
+        items.dedup_by(|next, previous| {
+            if next.0 == previous.0 {
+                std::mem::swap(next, previous);
+                true
+            } else {
+                false
+            }
+        });
+
+        progress.update(BulkLoadSteps::WriteItems);
+        let (atomic, step) = AtomicItemStep::new(items.len() as u64);
+        progress.update(step);
+        for (item, geometry) in items.iter() {
+            if cancel() {
+                return Err(Error::BuildCanceled);
+            }
+            self.item_db()
+                .put_with_flags(wtxn, PutFlags::APPEND, item, geometry)?;
+            atomic.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(items);
+
+        let cells = {
+            // The items are iterated in the order of their ids
+            let shapes = self.items(wtxn)?.collect::<Result<Vec<_>, _>>()?;
+            self.compute_cells_in_memory(&shapes, cancel, progress)?
+        };
+
+        progress.update(BulkLoadSteps::WriteCells);
+        let (atomic, step) = AtomicCellStep::new(cells.len() as u64);
+        progress.update(step);
+        for (key, bitmap) in cells {
+            if cancel() {
+                return Err(Error::BuildCanceled);
+            }
+            self.cell_db()
+                .put_with_flags(wtxn, PutFlags::APPEND, &key, &bitmap)?;
+            atomic.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.update_metadata(wtxn)?;
+        Ok(())
+    }
+
+    /// Compute all the cells and belly cells of the items, sorted in the order of the cell database.
+    /// The cells are split resolution by resolution so a cell receives all its items before we decide to split it.
+    fn compute_cells_in_memory(
+        &self,
+        shapes: &[(ItemId, Zerometry)],
+        cancel: &(impl Fn() -> bool + Send + Sync),
+        progress: &impl Progress,
+    ) -> Result<Vec<(Key, RoaringBitmap)>> {
+        progress.update(BulkLoadSteps::InsertItemsAtLevelZero);
+        let (atomic, step) = AtomicItemStep::new(shapes.len() as u64);
+        progress.update(step);
+        let (cells, bellies) = shapes
+            .par_iter()
+            .try_fold(
+                || (CellsMap::new(), CellsMap::new()),
+                |(mut cells, mut bellies), &(item, shape)| -> Result<_> {
+                    if cancel() {
+                        return Err(Error::BuildCanceled);
+                    }
+                    let mut cells_vec = Vec::new();
+                    let mut belly_vec = Vec::new();
+                    Self::explode_level_zero_geo(item, shape, &mut cells_vec, &mut belly_vec)?;
+                    for cell in cells_vec {
+                        cells.entry(cell).or_default().insert(item);
+                    }
+                    for cell in belly_vec {
+                        bellies.entry(cell).or_default().insert(item);
+                    }
+                    atomic.fetch_add(1, Ordering::Relaxed);
+                    Ok((cells, bellies))
+                },
+            )
+            .try_reduce(
+                || (CellsMap::new(), CellsMap::new()),
+                |(l_cells, l_bellies), (r_cells, r_bellies)| {
+                    Ok((merge(l_cells, r_cells), merge(l_bellies, r_bellies)))
+                },
+            )?;
+
+        progress.update(BulkLoadSteps::InsertItemsRecursively);
+        let mut ret: Vec<_> = bellies
+            .into_iter()
+            .map(|(cell, bitmap)| (Key::Belly(cell), bitmap))
+            .collect();
+        let mut current_resolution = cells;
+        while !current_resolution.is_empty() {
+            if cancel() {
+                return Err(Error::BuildCanceled);
+            }
+            let (next_resolution, bellies) = current_resolution
+                .par_iter()
+                .filter(|(cell, items)| self.settings.must_split(items.len(), cell.resolution()))
+                .map(|(&cell, items)| split_cell(shapes, cell, items))
+                .try_reduce(
+                    || (CellsMap::new(), CellsMap::new()),
+                    |(l_cells, l_bellies), (r_cells, r_bellies)| {
+                        Ok((merge(l_cells, r_cells), merge(l_bellies, r_bellies)))
+                    },
+                )?;
+            ret.extend(
+                current_resolution
+                    .into_iter()
+                    .map(|(cell, bitmap)| (Key::Cell(cell), bitmap)),
+            );
+            ret.extend(
+                bellies
+                    .into_iter()
+                    .map(|(cell, bitmap)| (Key::Belly(cell), bitmap)),
+            );
+            current_resolution = next_resolution;
+        }
+
+        ret.sort_unstable_by_key(|(key, _)| match key {
+            Key::Cell(cell) => (u64::from(*cell), KeyVariant::Cell as u8),
+            Key::Belly(cell) => (u64::from(*cell), KeyVariant::Belly as u8),
+        });
+        Ok(ret)
+    }
+}
+
+/// Dispatch the items of a cell in its children.
+/// Returns the items of each child and the items of the belly of each child.
+fn split_cell(
+    shapes: &[(ItemId, Zerometry)],
+    cell: CellIndex,
+    items: &RoaringBitmap,
+) -> Result<(CellsMap, CellsMap)> {
+    let mut cells = CellsMap::new();
+    let mut bellies = CellsMap::new();
+    let Some(children) = get_children_cells(cell)? else {
+        return Ok((cells, bellies));
+    };
+    for child in children {
+        let child_shape = MultiPolygon::from(child);
+        for item in items.iter() {
+            let shape = shapes
+                .binary_search_by_key(&item, |(item, _)| *item)
+                .map(|idx| shapes[idx].1)
+                .map_err(|_| Error::InternalDocIdMissing(item, pos!()))?;
+            let relation = shape.relation(
+                &child_shape,
+                InputRelation {
+                    // we don't need to know if we're being strictly contained or not
+                    strict_contained: false,
+                    ..InputRelation::all()
+                },
+            );
+            if relation.strict_contains.unwrap_or_default() {
+                bellies.entry(child).or_default().insert(item);
+            } else if relation.any_relation() {
+                cells.entry(child).or_default().insert(item);
+            }
+        }
+    }
+    Ok((cells, bellies))
+}
+
+fn merge(mut left: CellsMap, right: CellsMap) -> CellsMap {
+    for (cell, items) in right {
+        *left.entry(cell).or_default() |= items;
+    }
+    left
+}
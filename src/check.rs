@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use geo::MultiPolygon;
+use h3o::{CellIndex, Resolution};
+use heed::{RoTxn, types::DecodeIgnore};
+use roaring::RoaringBitmap;
+use zerometry::{InputRelation, RelationBetweenShapes};
+
+use crate::{Cellulite, ItemId, Result, builder::get_children_cells, keys::Key};
+
+/// A broken invariant of the database, see [`Cellulite::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The item is missing from a level-zero cell it intersects, or from the belly of a level-zero cell it contains.
+    ItemMissingFromLevelZero {
+        item: ItemId,
+        cell: CellIndex,
+        belly: bool,
+    },
+    /// The cell is stored in the database but none of its parents were split, the queries will never reach it.
+    /// It happens after deleting items and can be fixed with [`Cellulite::compact`].
+    UnreachableCell { cell: CellIndex, belly: bool },
+    /// The item is in the belly of a cell it doesn't contain.
+    BellyNotContained { item: ItemId, cell: CellIndex },
+    /// The item is in a cell it doesn't intersect.
+    CellNotIntersected { item: ItemId, cell: CellIndex },
+    /// The item is in a cell but in none of the split parents it could come from.
+    ItemMissingFromParent {
+        item: ItemId,
+        cell: CellIndex,
+        belly: bool,
+    },
+    /// The cell references an item that doesn't exist in the items database.
+    UnknownItem {
+        item: ItemId,
+        cell: CellIndex,
+        belly: bool,
+    },
+    /// The updates database contains this many items, the database must be built with [`Cellulite::build`].
+    PendingUpdates(u64),
+}
+
+impl Cellulite {
+    /// Check the invariants the database relies on and return all the problems found.
+    /// It's slow since all the items and cells are read, but nothing is modified.
+    pub fn check(&self, rtxn: &RoTxn) -> Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+
+        // 1. Nothing should be waiting to be built
+        let pending = self.update.len(rtxn)?;
+        if pending > 0 {
+            problems.push(Problem::PendingUpdates(pending));
+        }
+
+        // 2. Every item must be in the level-zero cells covering it
+        let mut items = RoaringBitmap::new();
+        let mut updated = RoaringBitmap::new();
+        for ret in self.update.remap_data_type::<DecodeIgnore>().iter(rtxn)? {
+            let (item, ()) = ret?;
+            updated.insert(item);
+        }
+        let mut cells = Vec::new();
+        let mut bellies = Vec::new();
+        for ret in self.items(rtxn)? {
+            let (item, shape) = ret?;
+            items.insert(item);
+            // The items that were not built yet are not in the cells
+            if updated.contains(item) {
+                continue;
+            }
+            cells.clear();
+            bellies.clear();
+            Self::explode_level_zero_geo(item, shape, &mut cells, &mut bellies)?;
+            for (cell, belly) in cells
+                .iter()
+                .map(|cell| (*cell, false))
+                .chain(bellies.iter().map(|cell| (*cell, true)))
+            {
+                let key = if belly {
+                    Key::Belly(cell)
+                } else {
+                    Key::Cell(cell)
+                };
+                let bitmap = self.cell_db().get(rtxn, &key)?.unwrap_or_default();
+                if !bitmap.contains(item) {
+                    problems.push(Problem::ItemMissingFromLevelZero { item, cell, belly });
+                }
+            }
+        }
+
+        // 3. Every cell must be reachable and only reference existing items
+        let mut cells_len = HashMap::new();
+        for ret in self.cell_db().iter(rtxn)? {
+            if let (Key::Cell(cell), bitmap) = ret? {
+                cells_len.insert(cell, bitmap.len());
+            }
+        }
+        let reachable = self.reachable_cells(&cells_len, &|| false)?;
+
+        // The items of a cell are dispatched between its children once it's split
+        let mut from_parents: HashMap<CellIndex, RoaringBitmap> = HashMap::new();
+        for ret in self.cell_db().iter(rtxn)? {
+            let (Key::Cell(cell), bitmap) = ret? else {
+                continue;
+            };
+            if !reachable.contains(&cell)
+                || !self.settings.must_split(bitmap.len(), cell.resolution())
+            {
+                continue;
+            }
+            for child in get_children_cells(cell)?.unwrap_or_default() {
+                *from_parents.entry(child).or_default() |= &bitmap;
+            }
+        }
+
+        for ret in self.cell_db().iter(rtxn)? {
+            let (key, bitmap) = ret?;
+            let (cell, belly) = match key {
+                Key::Cell(cell) => (cell, false),
+                Key::Belly(cell) => (cell, true),
+            };
+            if !reachable.contains(&cell) {
+                problems.push(Problem::UnreachableCell { cell, belly });
+            } else if cell.resolution() != Resolution::Zero {
+                let from_parents = from_parents.get(&cell).cloned().unwrap_or_default();
+                for item in &bitmap - from_parents {
+                    problems.push(Problem::ItemMissingFromParent { item, cell, belly });
+                }
+            }
+            for item in &bitmap - &items {
+                problems.push(Problem::UnknownItem { item, cell, belly });
+            }
+
+            // 4. The items of a cell must intersect it, and the items in its belly must contain it
+            let cell_shape = MultiPolygon::from(cell);
+            // The overwritten items are still in the cells of their previous shape until the next build
+            for item in &(&bitmap & &items) - &updated {
+                // safe to unwrap because we just checked the item exists
+                let shape = self.item_db().get(rtxn, &item)?.unwrap();
+                // Same relation as the one computed by the builder
+                let relation = shape.relation(
+                    &cell_shape,
+                    InputRelation {
+                        strict_contained: false,
+                        ..InputRelation::all()
+                    },
+                );
+                if !belly && !relation.any_relation() {
+                    problems.push(Problem::CellNotIntersected { item, cell });
+                }
+                // The level-zero bellies only require the item to contain the cell
+                let contains = if cell.resolution() == Resolution::Zero {
+                    relation.contains
+                } else {
+                    relation.strict_contains
+                };
+                if belly && !contains.unwrap_or_default() {
+                    problems.push(Problem::BellyNotContained { item, cell });
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+}
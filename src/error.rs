@@ -1,6 +1,6 @@
-use h3o::error::{InvalidGeometry, PlotterError};
+use h3o::error::{InvalidGeometry, InvalidLatLng, PlotterError};
 
-use crate::{ItemId, metadata::Version};
+use crate::{ItemId, metadata::FORMAT_VERSION};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -8,14 +8,39 @@ pub enum Error {
     #[error("The build was canceled")]
     BuildCanceled,
     #[error(
-        "Version mismatch while building, was expecting v{} but instead got v{}. Upgrade the version before building.",
-        Version::default(), .0
+        "Format version mismatch while building, was expecting v{} but instead got v{}. Upgrade the database before building.",
+        FORMAT_VERSION, .0
     )]
-    VersionMismatchOnBuild(Version),
+    VersionMismatchOnBuild(u32),
+    #[error(
+        "Cannot upgrade the database from the format v{} because it's more recent than the current format v{}.",
+        .0, FORMAT_VERSION
+    )]
+    UpgradeFromNewerVersion(u32),
     #[error(
         "Tried to open a cellulite database, but it's inner database don't exists yet. Call `create_from_env` first."
     )]
     DatabaseDoesntExists,
+    #[error(
+        "The properties database doesn't exist. Open the environment with `Cellulite::nb_dbs()` databases and call `upgrade` to create it."
+    )]
+    PropertiesDatabaseDoesntExists,
+    #[error("The bulk loader can only be used on an empty database.")]
+    BulkLoadOnNonEmptyDatabase,
+    #[error("The settings can only be changed before building the database.")]
+    SettingsChangeOnNonEmptyDatabase,
+    #[error("Invalid settings: {0}.")]
+    InvalidSettings(&'static str),
+    #[error(
+        "Cannot query the items contained in a `{0}` because it doesn't have an area. Only polygons, multi-polygons, rects, triangles and collections of them are supported."
+    )]
+    UnsupportedQueryGeometry(&'static str),
+    #[error(
+        "Item `{0}` has invalid coordinates (lat: {1}, lng: {2}). The latitude must be between -90 and 90 and the longitude between -180 and 180."
+    )]
+    InvalidCoordinates(ItemId, f64, f64),
+    #[error("Item `{0}` is not a valid zerometry: {1}.")]
+    InvalidZerometry(ItemId, String),
 
     // External errors, sometimes it's a user error and sometimes it's not
     #[error(transparent)]
@@ -23,7 +48,15 @@ pub enum Error {
     #[error(transparent)]
     InvalidGeometry(#[from] InvalidGeometry),
     #[error(transparent)]
+    InvalidQueryLatLng(#[from] InvalidLatLng),
+    #[error("Item `{0}` has an invalid coordinate: {1}")]
+    InvalidLatLng(ItemId, InvalidLatLng),
+    #[error(transparent)]
     InvalidGeoJson(#[from] Box<geojson::Error>),
+    #[error("Invalid WKT: {0}")]
+    InvalidWkt(String),
+    #[error("Invalid WKB: {0}")]
+    InvalidWkb(String),
 
     // Internal errors
     #[error("unexpected document id `{0}` missing at `{1}`")]
@@ -128,29 +128,92 @@ pub enum UpdateType {
     Delete = 1,
 }
 
-impl<'a> heed::BytesEncode<'a> for UpdateType {
-    type EItem = Self;
+/// An operation made on an item, waiting to be applied by the next build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub kind: UpdateType,
+    /// When the shape of an already indexed item is overwritten, the level-zero cells and bellies of
+    /// the indexed shape so the build can remove the item from them. They're empty otherwise.
+    pub previous_cells: Vec<CellIndex>,
+    pub previous_bellies: Vec<CellIndex>,
+}
 
-    fn bytes_encode(item: &Self::EItem) -> Result<Cow<'a, [u8]>, heed::BoxedError> {
-        Ok(Cow::Owned(vec![*item as u8]))
+impl Update {
+    pub fn new(kind: UpdateType) -> Self {
+        Self {
+            kind,
+            previous_cells: Vec::new(),
+            previous_bellies: Vec::new(),
+        }
     }
 }
 
-impl<'a> heed::BytesDecode<'a> for UpdateType {
-    type DItem = Self;
+/// Codec used to encode and decode the updates.
+///
+/// - The first byte is the type of the update
+/// - The next byte is the number of previous level-zero cells, if there is none the update stops here
+/// - Then the previous cells followed by the previous bellies are encoded as u64
+pub struct UpdateCodec;
+
+impl<'a> heed::BytesEncode<'a> for UpdateCodec {
+    type EItem = Update;
+
+    fn bytes_encode(item: &'a Self::EItem) -> Result<Cow<'a, [u8]>, heed::BoxedError> {
+        let Update {
+            kind,
+            previous_cells,
+            previous_bellies,
+        } = item;
+        if previous_cells.is_empty() && previous_bellies.is_empty() {
+            return Ok(Cow::Owned(vec![*kind as u8]));
+        }
+        // There are only 122 level-zero cells
+        let nb_cells = u8::try_from(previous_cells.len())?;
+        let mut ret = Vec::with_capacity(
+            2 + (previous_cells.len() + previous_bellies.len()) * size_of::<u64>(),
+        );
+        ret.push(*kind as u8);
+        ret.push(nb_cells);
+        for cell in previous_cells.iter().chain(previous_bellies) {
+            let cell: u64 = (*cell).into();
+            ret.extend_from_slice(&cell.to_be_bytes());
+        }
+        Ok(Cow::Owned(ret))
+    }
+}
+
+impl<'a> heed::BytesDecode<'a> for UpdateCodec {
+    type DItem = Update;
 
     fn bytes_decode(bytes: &'a [u8]) -> Result<Self::DItem, heed::BoxedError> {
-        match bytes {
-            [b] if *b == UpdateType::Insert as u8 => Ok(UpdateType::Insert),
-            [b] if *b == UpdateType::Delete as u8 => Ok(UpdateType::Delete),
+        let kind = match bytes.first() {
+            Some(b) if *b == UpdateType::Insert as u8 => UpdateType::Insert,
+            Some(b) if *b == UpdateType::Delete as u8 => UpdateType::Delete,
             _ => panic!("Invalid update type {bytes:?}"),
+        };
+        let mut update = Update::new(kind);
+        let Some((&nb_cells, cells)) = bytes[1..].split_first() else {
+            return Ok(update);
+        };
+        for (i, cell) in cells.chunks_exact(size_of::<u64>()).enumerate() {
+            let cell = BigEndian::read_u64(cell).try_into()?;
+            if i < nb_cells as usize {
+                update.previous_cells.push(cell);
+            } else {
+                update.previous_bellies.push(cell);
+            }
         }
+        Ok(update)
     }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MetadataKey {
     Version = 0,
+    FormatVersion = 1,
+    Threshold = 2,
+    MinResolution = 3,
+    MaxResolution = 4,
 }
 
 impl<'a> heed::BytesEncode<'a> for MetadataKey {
@@ -167,6 +230,10 @@ impl<'a> heed::BytesDecode<'a> for MetadataKey {
     fn bytes_decode(bytes: &'a [u8]) -> Result<Self::DItem, heed::BoxedError> {
         match bytes {
             [b] if *b == MetadataKey::Version as u8 => Ok(MetadataKey::Version),
+            [b] if *b == MetadataKey::FormatVersion as u8 => Ok(MetadataKey::FormatVersion),
+            [b] if *b == MetadataKey::Threshold as u8 => Ok(MetadataKey::Threshold),
+            [b] if *b == MetadataKey::MinResolution as u8 => Ok(MetadataKey::MinResolution),
+            [b] if *b == MetadataKey::MaxResolution as u8 => Ok(MetadataKey::MaxResolution),
             _ => panic!("Invalid metadata key {bytes:?}"),
         }
     }
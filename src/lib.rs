@@ -3,35 +3,44 @@ use std::collections::BTreeMap;
 
 use ::roaring::RoaringBitmap;
 use ::zerometry::Zerometry;
-use geo::{Densify, Geometry, Haversine};
-use geojson::GeoJson;
+use geo::{CoordsIter, Densify, Geometry, Haversine};
+use geo_traits::to_geo::ToGeoGeometry;
+use geojson::{Feature, FeatureCollection, GeoJson, JsonObject};
 use h3o::{CellIndex, Resolution};
 use heed::{
     DatabaseStat, Env, RoTxn, RwTxn, Unspecified,
     byteorder::BE,
-    types::{Bytes, U32},
+    types::{Bytes, DecodeIgnore, SerdeJson, U8, U32, U64},
 };
-use keys::{CellKeyCodec, ItemKeyCodec, Key, MetadataKey, UpdateType};
+use keys::{CellKeyCodec, ItemKeyCodec, Key, MetadataKey, Update, UpdateCodec, UpdateType};
 use metadata::{Version, VersionCodec};
+use wkt::TryFromWkt;
 
 mod builder;
+mod bulk_loader;
+mod check;
 mod error;
 pub(crate) mod keys;
 mod metadata;
 pub mod reader;
 pub mod roaring;
+mod shape;
+mod upgrade;
 pub mod zerometry;
 
 #[cfg(test)]
 mod test;
 
+pub use crate::check::Problem;
 pub use crate::error::Error;
+pub use crate::metadata::{FORMAT_VERSION, Settings};
 use crate::{roaring::RoaringBitmapCodec, zerometry::ZerometryCodec};
 
 pub type ItemDb = heed::Database<ItemKeyCodec, ZerometryCodec>;
 pub type CellDb = heed::Database<CellKeyCodec, RoaringBitmapCodec>;
-pub type UpdateDb = heed::Database<U32<BE>, UpdateType>;
+pub type UpdateDb = heed::Database<U32<BE>, UpdateCodec>;
 pub type MetadataDb = heed::Database<MetadataKey, Unspecified>;
+pub type PropertyDb = heed::Database<ItemKeyCodec, SerdeJson<JsonObject>>;
 pub type ItemId = u32;
 
 steppe::make_enum_progress! {
@@ -62,15 +71,17 @@ pub struct Cellulite {
     pub(crate) update: UpdateDb,
     /// Contains all the metadata related to the database.
     pub(crate) metadata: MetadataDb,
+    /// Links the item IDs with their properties, an item doesn't necessarily have properties.
+    /// The databases written in the format v1 don't have it until they're upgraded.
+    pub(crate) properties: Option<PropertyDb>,
 
-    /// After how many elements should we break a cell into sub-cells
-    /// This is only available for the test and visualizing tools to use it.
-    pub threshold: u64,
+    /// The settings used to build the database, they're stored in the metadata database.
+    pub(crate) settings: Settings,
 }
 
 impl Cellulite {
     pub const fn nb_dbs() -> u32 {
-        4
+        5
     }
 
     pub fn item_db_stats(&self, rtxn: &RoTxn) -> heed::Result<DatabaseStat> {
@@ -89,28 +100,30 @@ impl Cellulite {
         self.metadata.stat(rtxn)
     }
 
+    pub fn properties_db_stats(&self, rtxn: &RoTxn) -> heed::Result<Option<DatabaseStat>> {
+        self.properties.map(|db| db.stat(rtxn)).transpose()
+    }
+
     pub const fn default_threshold() -> u64 {
         200
     }
 
     /// Create all the databases required for cellulite to work.
     /// The prefix lets you to hold multiple cellulite database in a single environment.
+    ///
+    /// The environment must be opened with at least [`Self::nb_dbs`] databases, otherwise an error is returned.
     pub fn create_from_env<Tls>(env: &Env<Tls>, wtxn: &mut RwTxn, prefix: &str) -> Result<Self> {
         let item = env.create_database(wtxn, Some(&format!("{prefix}-item")))?;
         let cell = env.create_database(wtxn, Some(&format!("{prefix}-cell")))?;
         let update = env.create_database(wtxn, Some(&format!("{prefix}-update")))?;
         let metadata = env.create_database(wtxn, Some(&format!("{prefix}-metadata")))?;
-        Ok(Self {
-            item,
-            cell,
-            update,
-            metadata,
-            threshold: Self::default_threshold(),
-        })
+        let properties = env.create_database(wtxn, Some(&format!("{prefix}-properties")))?;
+        Self::from_dbs(wtxn, item, cell, update, metadata, Some(properties))
     }
 
     /// Open all the databases required for cellulite to work, return an error if any of the required database doesn't exists.
     /// The prefix lets you to hold multiple cellulite database in a single environment.
+    /// The properties database is optional since it didn't exist in the format v1, see [`Self::upgrade`] to create it.
     pub fn open_from_env<Tls>(env: &Env<Tls>, rtxn: &RoTxn, prefix: &str) -> Result<Self> {
         let item = env
             .open_database(rtxn, Some(&format!("{prefix}-item")))?
@@ -124,24 +137,30 @@ impl Cellulite {
         let metadata = env
             .open_database(rtxn, Some(&format!("{prefix}-metadata")))?
             .ok_or(Error::DatabaseDoesntExists)?;
-        Ok(Self {
-            item,
-            cell,
-            update,
-            metadata,
-            threshold: Self::default_threshold(),
-        })
+        let properties = env.open_database(rtxn, Some(&format!("{prefix}-properties")))?;
+        Self::from_dbs(rtxn, item, cell, update, metadata, properties)
     }
 
     /// Create the cellulite struct from already opened databases.
-    pub fn from_dbs(item: ItemDb, cell: CellDb, update: UpdateDb, metadata: MetadataDb) -> Self {
-        Self {
+    /// The settings are loaded from the metadata database, return an error if they're invalid.
+    pub fn from_dbs(
+        rtxn: &RoTxn,
+        item: ItemDb,
+        cell: CellDb,
+        update: UpdateDb,
+        metadata: MetadataDb,
+        properties: Option<PropertyDb>,
+    ) -> Result<Self> {
+        let mut this = Self {
             item,
             cell,
             update,
             metadata,
-            threshold: Self::default_threshold(),
-        }
+            properties,
+            settings: Settings::default(),
+        };
+        this.settings = this.stored_settings(rtxn)?;
+        Ok(this)
     }
 
     /// Clear all the databases.
@@ -150,6 +169,9 @@ impl Cellulite {
         self.cell.clear(wtxn)?;
         self.update.clear(wtxn)?;
         self.metadata.clear(wtxn)?;
+        if let Some(properties) = self.properties {
+            properties.clear(wtxn)?;
+        }
         Ok(())
     }
 
@@ -163,6 +185,11 @@ impl Cellulite {
         self.cell
     }
 
+    #[inline]
+    fn properties_db(&self) -> Result<PropertyDb> {
+        self.properties.ok_or(Error::PropertiesDatabaseDoesntExists)
+    }
+
     /// Return the version of the cellulite database.
     pub fn get_version(&self, rtxn: &RoTxn) -> heed::Result<Version> {
         self.metadata
@@ -179,6 +206,100 @@ impl Cellulite {
             .put(wtxn, &MetadataKey::Version, version)
     }
 
+    /// Return the version of the on-disk format of the database, see [`FORMAT_VERSION`].
+    pub fn get_format_version(&self, rtxn: &RoTxn) -> heed::Result<u32> {
+        let format = self
+            .metadata
+            .remap_data_type::<U32<BE>>()
+            .get(rtxn, &MetadataKey::FormatVersion)?;
+        let version = self
+            .metadata
+            .remap_data_type::<DecodeIgnore>()
+            .get(rtxn, &MetadataKey::Version)?;
+        Ok(match (format, version) {
+            (Some(format), _) => format,
+            // The format version was introduced after v0.2.0, all the databases written before share the first format
+            (None, Some(())) => 1,
+            // If there is no version in the database it means we never wrote anything to the database
+            // and we're at the current format
+            (None, None) => FORMAT_VERSION,
+        })
+    }
+
+    fn set_format_version(&self, wtxn: &mut RwTxn, format: u32) -> heed::Result<()> {
+        self.metadata
+            .remap_data_type::<U32<BE>>()
+            .put(wtxn, &MetadataKey::FormatVersion, &format)
+    }
+
+    /// Mark the database as written by the current version of cellulite, in the current format.
+    /// The settings are stored as well so the database is always read with the settings it was built with.
+    fn update_metadata(&self, wtxn: &mut RwTxn) -> heed::Result<()> {
+        self.set_version(wtxn, &Version::default())?;
+        self.set_format_version(wtxn, FORMAT_VERSION)?;
+        self.store_settings(wtxn)
+    }
+
+    /// Store the settings in the metadata database, the version and format of the database are left untouched.
+    fn store_settings(&self, wtxn: &mut RwTxn) -> heed::Result<()> {
+        self.metadata.remap_data_type::<U64<BE>>().put(
+            wtxn,
+            &MetadataKey::Threshold,
+            &self.settings.threshold,
+        )?;
+        let resolutions = self.metadata.remap_data_type::<U8>();
+        resolutions.put(
+            wtxn,
+            &MetadataKey::MinResolution,
+            &self.settings.min_resolution.into(),
+        )?;
+        resolutions.put(
+            wtxn,
+            &MetadataKey::MaxResolution,
+            &self.settings.max_resolution.into(),
+        )
+    }
+
+    /// Return the settings used to build the database.
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    /// Change the settings used to build the database and store them in the metadata database.
+    /// Since the cells depends on the settings, they can only be changed before the first build.
+    pub fn set_settings(&mut self, wtxn: &mut RwTxn, settings: Settings) -> Result<()> {
+        settings.validate()?;
+        if settings != self.settings && !self.cell.is_empty(wtxn)? {
+            return Err(Error::SettingsChangeOnNonEmptyDatabase);
+        }
+        self.settings = settings;
+        self.store_settings(wtxn)?;
+        Ok(())
+    }
+
+    /// Load the settings stored in the metadata database, the missing settings are set to their default value.
+    fn stored_settings(&self, rtxn: &RoTxn) -> Result<Settings> {
+        let mut settings = Settings::default();
+        if let Some(threshold) = self
+            .metadata
+            .remap_data_type::<U64<BE>>()
+            .get(rtxn, &MetadataKey::Threshold)?
+        {
+            settings.threshold = threshold;
+        }
+        let resolutions = self.metadata.remap_data_type::<U8>();
+        if let Some(resolution) = resolutions.get(rtxn, &MetadataKey::MinResolution)? {
+            settings.min_resolution = Resolution::try_from(resolution)
+                .map_err(|_| Error::InvalidSettings("invalid minimum resolution"))?;
+        }
+        if let Some(resolution) = resolutions.get(rtxn, &MetadataKey::MaxResolution)? {
+            settings.max_resolution = Resolution::try_from(resolution)
+                .map_err(|_| Error::InvalidSettings("invalid maximum resolution"))?;
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
     /// Return all the cells used internally in the database
     pub fn inner_db_cells<'a>(
         &self,
@@ -228,6 +349,64 @@ impl Cellulite {
         self.item_db().get(rtxn, &item).map_err(Error::from)
     }
 
+    /// Set the properties of an item, replacing its previous properties.
+    /// Unlike the geometry, the properties don't need a [`Self::build`] to be updated.
+    /// Since adding an item removes its properties, they must be set after the geometry.
+    pub fn set_properties(
+        &self,
+        wtxn: &mut RwTxn,
+        item: ItemId,
+        properties: &JsonObject,
+    ) -> Result<()> {
+        self.properties_db()?.put(wtxn, &item, properties)?;
+        Ok(())
+    }
+
+    /// Remove the properties of an item, its geometry is kept.
+    pub fn remove_properties(&self, wtxn: &mut RwTxn, item: ItemId) -> Result<()> {
+        if let Some(properties) = self.properties {
+            properties.delete(wtxn, &item)?;
+        }
+        Ok(())
+    }
+
+    /// Return the properties of an item. Returns `None` if the item doesn't have any properties.
+    pub fn properties(&self, rtxn: &RoTxn, item: ItemId) -> Result<Option<JsonObject>> {
+        match self.properties {
+            Some(properties) => properties.get(rtxn, &item).map_err(Error::from),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the item as a geojson feature containing its id and properties. Returns `None` if the item doesn't exists.
+    pub fn feature(&self, rtxn: &RoTxn, item: ItemId) -> Result<Option<Feature>> {
+        let Some(shape) = self.item(rtxn, item)? else {
+            return Ok(None);
+        };
+        let geometry = crate::zerometry::to_geometry(shape);
+        Ok(Some(Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::new(geojson::Value::from(&geometry))),
+            id: Some(geojson::feature::Id::Number(item.into())),
+            properties: self.properties(rtxn, item)?,
+            foreign_members: None,
+        }))
+    }
+
+    /// Return the items as a collection of geojson features, the items that doesn't exists are ignored.
+    /// It's useful to return the result of a query with the properties of the items.
+    pub fn features(&self, rtxn: &RoTxn, items: &RoaringBitmap) -> Result<FeatureCollection> {
+        let features = items
+            .iter()
+            .filter_map(|item| self.feature(rtxn, item).transpose())
+            .collect::<Result<_>>()?;
+        Ok(FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        })
+    }
+
     /// Iterate over all the items in the database
     pub fn items<'a>(
         &self,
@@ -237,28 +416,100 @@ impl Cellulite {
     }
 
     /// Insert a geojson to the database. The geojson won't be stored as-is and cannot be returned later.
+    /// Like all the other `add` methods, it replaces the item if it already exists and removes its properties,
+    /// then the properties of the feature are stored, if there is any.
     /// For the item to be searchable you must [`Self::build`] the database afterward.
+    /// Returns an error if the geojson cannot be converted to a geometry or if its coordinates are invalid.
     pub fn add(&self, wtxn: &mut RwTxn, item: ItemId, geo: &GeoJson) -> Result<()> {
-        let geom = geo_types::Geometry::<f64>::try_from(geo.clone()).unwrap();
-        self.item_db().put(wtxn, &item, &geom)?;
-        self.update.put(wtxn, &item, &UpdateType::Insert)?;
+        let geom = geo_types::Geometry::<f64>::try_from(geo.clone()).map_err(Box::new)?;
+        let properties = match geo {
+            GeoJson::Feature(Feature {
+                properties: Some(properties),
+                ..
+            }) => Some(self.properties_db().map(|db| (db, properties))?),
+            _ => None,
+        };
+        self.add_geometry(wtxn, item, &geom)?;
+        if let Some((db, properties)) = properties {
+            db.put(wtxn, &item, properties)?;
+        }
         Ok(())
     }
 
-    /// The `geo` must be a valid `Zerometry` otherwise the database will be corrupted.
+    /// Insert a geometry to the database, the item is replaced if it already exists and its properties are removed.
+    /// For the item to be searchable you must [`Self::build`] the database afterward.
+    /// Returns an error if its coordinates are invalid.
+    pub fn add_geometry(&self, wtxn: &mut RwTxn, item: ItemId, geom: &Geometry) -> Result<()> {
+        validate_coordinates(item, geom)?;
+        let update = self.insert_update(wtxn, item)?;
+        self.remove_properties(wtxn, item)?;
+        self.item_db().put(wtxn, &item, geom)?;
+        self.update.put(wtxn, &item, &update)?;
+        Ok(())
+    }
+
+    /// Insert a geometry in the WKT format to the database, see [`Self::add_geometry`].
+    /// For the item to be searchable you must [`Self::build`] the database afterward.
+    pub fn add_wkt(&self, wtxn: &mut RwTxn, item: ItemId, wkt: &str) -> Result<()> {
+        let geom =
+            Geometry::try_from_wkt_str(wkt).map_err(|err| Error::InvalidWkt(err.to_string()))?;
+        self.add_geometry(wtxn, item, &geom)
+    }
+
+    /// Insert a geometry in the WKB format to the database. The extended WKB of PostGIS is supported as well
+    /// but the SRID, Z and M values are ignored, see [`Self::add_geometry`].
+    /// For the item to be searchable you must [`Self::build`] the database afterward.
+    pub fn add_wkb(&self, wtxn: &mut RwTxn, item: ItemId, wkb: &[u8]) -> Result<()> {
+        let geom = ::wkb::reader::read_wkb(wkb)
+            .map_err(|err| Error::InvalidWkb(err.to_string()))?
+            .try_to_geometry()
+            .ok_or_else(|| Error::InvalidWkb(String::from("empty points are not supported")))?;
+        self.add_geometry(wtxn, item, &geom)
+    }
+
+    /// Insert an already encoded `Zerometry` to the database, see [`Self::add_geometry`].
+    /// The buffer is validated before being inserted, see [`Self::add_raw_zerometry_unchecked`] to skip the validation.
     /// For the item to be searchable you must [`Self::build`] the database afterward.
     pub fn add_raw_zerometry(&self, wtxn: &mut RwTxn, item: ItemId, geo: &[u8]) -> Result<()> {
+        let geom = crate::zerometry::validate_zerometry(geo)
+            .map_err(|reason| Error::InvalidZerometry(item, reason))?;
+        validate_coordinates(item, &geom)?;
+        // Safe because we just validated the buffer
+        unsafe { self.add_raw_zerometry_unchecked(wtxn, item, geo) }
+    }
+
+    /// Insert an already encoded `Zerometry` to the database without validating it.
+    /// For the item to be searchable you must [`Self::build`] the database afterward.
+    ///
+    /// # Safety
+    ///
+    /// The `geo` must be a valid `Zerometry` with valid coordinates, otherwise the database
+    /// will be corrupted and reading the item is undefined behavior.
+    pub unsafe fn add_raw_zerometry_unchecked(
+        &self,
+        wtxn: &mut RwTxn,
+        item: ItemId,
+        geo: &[u8],
+    ) -> Result<()> {
+        let update = self.insert_update(wtxn, item)?;
+        self.remove_properties(wtxn, item)?;
         self.item_db()
             .remap_data_type::<Bytes>()
             .put(wtxn, &item, geo)?;
-        self.update.put(wtxn, &item, &UpdateType::Insert)?;
+        self.update.put(wtxn, &item, &update)?;
         Ok(())
     }
 
     /// Delete an item by its id.
-    /// For the item to be removed you must [`Self::build`] the database afterward.
+    /// For the item and its properties to be removed you must [`Self::build`] the database afterward.
     pub fn delete(&self, wtxn: &mut RwTxn, item: ItemId) -> Result<()> {
-        self.update.put(wtxn, &item, &UpdateType::Delete)?;
+        // The previous cells of an overwritten item must be kept since its indexed shape is gone
+        let mut update = self
+            .update
+            .get(wtxn, &item)?
+            .unwrap_or_else(|| Update::new(UpdateType::Delete));
+        update.kind = UpdateType::Delete;
+        self.update.put(wtxn, &item, &update)?;
         Ok(())
     }
 
@@ -304,6 +555,18 @@ pub struct Stats {
     pub belly_cells_by_resolution: BTreeMap<Resolution, usize>,
 }
 
+/// Ensure all the coordinates of the geometry are valid latitudes and longitudes.
+fn validate_coordinates(item: ItemId, geom: &Geometry) -> Result<()> {
+    for coord in geom.coords_iter() {
+        let (lng, lat) = (coord.x, coord.y);
+        // also catches NaN since all the comparisons with NaN are false
+        if !((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lng)) {
+            return Err(Error::InvalidCoordinates(item, lat, lng));
+        }
+    }
+    Ok(())
+}
+
 pub fn densify_geom(geom: &mut Geometry) {
     match geom {
         Geometry::Line(line) => {
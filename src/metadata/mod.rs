@@ -2,10 +2,18 @@ use std::borrow::Cow;
 use std::fmt;
 use std::mem::size_of;
 
+use h3o::Resolution;
 use heed::BoxedError;
 use heed::byteorder::{BigEndian, ByteOrder};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::{Cellulite, Error};
+
+/// The version of the on-disk format of the database.
+/// It's independent of the crate version and must only be bumped when the format changes,
+/// the databases at an older format must then be migrated with [`crate::Cellulite::upgrade`].
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Version {
     pub major: u32,
     pub minor: u32,
@@ -18,14 +26,6 @@ impl fmt::Display for Version {
     }
 }
 
-impl Version {
-    pub fn compatible_with_current_for_build(&self) -> bool {
-        let current = Version::default();
-        // for now there was no db breaking
-        self == &current
-    }
-}
-
 impl Default for Version {
     fn default() -> Self {
         Version {
@@ -36,6 +36,68 @@ impl Default for Version {
     }
 }
 
+/// The settings used to build a database, see [`crate::Cellulite::set_settings`].
+/// New settings may be added in the future, start from [`Settings::default`] and use the `with_` methods to create them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Settings {
+    /// After how many items a cell is split into sub-cells.
+    pub threshold: u64,
+    /// The cells are always split until this resolution, even if they contain less items than the threshold.
+    pub min_resolution: Resolution,
+    /// The cells are never split past this resolution, even if they contain more items than the threshold.
+    /// A lower resolution creates less cells but more items must be double checked by the queries.
+    pub max_resolution: Resolution,
+}
+
+impl Settings {
+    pub fn with_threshold(self, threshold: u64) -> Self {
+        Self { threshold, ..self }
+    }
+
+    pub fn with_min_resolution(self, min_resolution: Resolution) -> Self {
+        Self {
+            min_resolution,
+            ..self
+        }
+    }
+
+    pub fn with_max_resolution(self, max_resolution: Resolution) -> Self {
+        Self {
+            max_resolution,
+            ..self
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.threshold == 0 {
+            return Err(Error::InvalidSettings("the threshold must be at least 1"));
+        }
+        if self.min_resolution > self.max_resolution {
+            return Err(Error::InvalidSettings(
+                "the minimum resolution cannot be greater than the maximum resolution",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return `true` if a cell at `resolution` containing `len` items must be split into sub-cells.
+    pub(crate) fn must_split(&self, len: u64, resolution: Resolution) -> bool {
+        resolution < self.max_resolution
+            && (len >= self.threshold || resolution < self.min_resolution)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            threshold: Cellulite::default_threshold(),
+            min_resolution: Resolution::Zero,
+            max_resolution: Resolution::Fifteen,
+        }
+    }
+}
+
 pub enum VersionCodec {}
 
 impl<'a> heed::BytesEncode<'a> for VersionCodec {
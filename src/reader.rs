@@ -1,15 +1,28 @@
-use std::collections::{HashSet, VecDeque};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
+};
 
-use geo::{Densify, Destination, Haversine, MultiPolygon, Point, Polygon, Relate};
-use h3o::{
-    CellIndex, Resolution,
-    geom::{ContainmentMode, TilerBuilder},
+use geo::{
+    Coord, Destination, Distance, Geometry, Haversine, Intersects, MultiPolygon, Point, Polygon,
+    Rect, Relate,
 };
-use heed::RoTxn;
+use geojson::GeoJson;
+use h3o::{CellIndex, LatLng, Resolution};
+use heed::{RoTxn, types::DecodeIgnore};
+use ordered_float::OrderedFloat;
 use roaring::RoaringBitmap;
-use zerometry::RelationBetweenShapes;
+use zerometry::{InputRelation, RelationBetweenShapes, Zerometry};
 
-use crate::{Cellulite, Result};
+use crate::{
+    Cellulite, Error, ItemId, Result,
+    builder::get_children_cells,
+    pos,
+    shape::{
+        BboxShape, CellRelation, CircleShape, CorridorShape, PolygonShape, QueryShape,
+        cell_lower_bound_distance, cover_cell, distance_to_geometry,
+    },
+};
 
 impl Cellulite {
     pub fn in_shape(&self, rtxn: &RoTxn, polygon: &Polygon) -> Result<RoaringBitmap> {
@@ -18,117 +31,300 @@ impl Cellulite {
 
     /// Return all the items that intersects or are contained in the specified polygon.
     /// The `inspector` lets you see how the search was made internally.
-    // The strategy to retrieve the points in a shape is to:
-    // 1. Retrieve all the cell@res0 that contains the shape
-    // 2. Iterate over these cells
-    //  2.1.If a cell fit entirely *inside* the shape, add all its items to the result
-    //  2.2 Otherwise:
-    //   - If the cell is a leaf => iterate over all of its point and add the one that fits in the shape to the result
-    //   - Otherwise, increase the precision and iterate on the range of cells => repeat step 2
     pub fn in_shape_with_inspector(
         &self,
         rtxn: &RoTxn,
         polygon: &Polygon,
-        mut inspector: impl FnMut((FilteringStep, CellIndex)),
+        inspector: impl FnMut((FilteringStep, CellIndex)),
     ) -> Result<RoaringBitmap> {
-        // Roughly equivalent to the number of children we would have in three cells
-        const BECOME_TOO_LARGE: usize = 60;
+        let polygon = MultiPolygon::new(vec![polygon.clone()]);
+        self.in_multi_polygon_with_inspector(rtxn, &polygon, Predicate::Intersects, None, inspector)
+    }
 
-        let polygon = Haversine.densify(polygon, 1_000.0);
-        let mut tiler = TilerBuilder::new(Resolution::Zero)
-            .containment_mode(ContainmentMode::Covers)
-            .build();
-        tiler.add(polygon.clone())?;
+    /// Return all the items that intersects or are contained in the specified polygon and are accepted by the filter.
+    /// The filter is applied before the expensive check of the shape of the items, see [`Filter`].
+    pub fn in_shape_with_filter(
+        &self,
+        rtxn: &RoTxn,
+        polygon: &Polygon,
+        filter: &Filter,
+    ) -> Result<RoaringBitmap> {
+        let polygon = MultiPolygon::new(vec![polygon.clone()]);
+        self.in_multi_polygon_with_inspector(
+            rtxn,
+            &polygon,
+            Predicate::Intersects,
+            Some(filter),
+            &mut |_| (),
+        )
+    }
 
-        let mut ret = RoaringBitmap::new();
-        let mut double_check = RoaringBitmap::new();
-        let mut to_explore: VecDeque<_> = tiler.into_coverage().collect();
-        let mut already_explored: HashSet<CellIndex> = HashSet::with_capacity(to_explore.len());
-        let mut too_large = false;
-        let mut already_tiled = None;
+    /// Return all the items that intersects or are contained in the specified geometry.
+    /// The geometry must have an area, see [`Self::in_geometry_with_inspector`].
+    pub fn in_geometry(&self, rtxn: &RoTxn, geometry: &Geometry) -> Result<RoaringBitmap> {
+        self.in_geometry_with_inspector(rtxn, geometry, &mut |_| ())
+    }
 
-        while let Some(cell) = to_explore.pop_front() {
-            if !already_explored.insert(cell) {
-                continue;
-            }
+    /// Return all the items that intersects or are contained in the specified geometry.
+    /// Only the geometries with an area are supported: polygons (with or without holes), multi-polygons,
+    /// rects, triangles and geometry collections made of them.
+    /// The `inspector` lets you see how the search was made internally.
+    pub fn in_geometry_with_inspector(
+        &self,
+        rtxn: &RoTxn,
+        geometry: &Geometry,
+        inspector: impl FnMut((FilteringStep, CellIndex)),
+    ) -> Result<RoaringBitmap> {
+        let mut polygons = Vec::new();
+        collect_polygons(geometry, &mut polygons)?;
+        self.in_multi_polygon_with_inspector(
+            rtxn,
+            &MultiPolygon::new(polygons),
+            Predicate::Intersects,
+            None,
+            inspector,
+        )
+    }
 
-            let (cell_items, belly_items) =
-                crate::keys::retrieve_cell_and_belly(rtxn, &self.cell_db(), cell)?;
+    /// Return all the items that intersects or are contained in the specified geometry and are accepted by the filter.
+    /// The geometry must have an area, see [`Self::in_geometry_with_inspector`].
+    pub fn in_geometry_with_filter(
+        &self,
+        rtxn: &RoTxn,
+        geometry: &Geometry,
+        filter: &Filter,
+    ) -> Result<RoaringBitmap> {
+        let mut polygons = Vec::new();
+        collect_polygons(geometry, &mut polygons)?;
+        self.in_multi_polygon_with_inspector(
+            rtxn,
+            &MultiPolygon::new(polygons),
+            Predicate::Intersects,
+            Some(filter),
+            &mut |_| (),
+        )
+    }
 
-            if cell_items.is_none() && belly_items.is_none() {
-                (inspector)((FilteringStep::NotPresentInDB, cell));
-                continue;
+    /// Return all the items that have the specified spatial relation with the geometry.
+    /// The geometry must have an area, see [`Self::in_geometry_with_inspector`].
+    pub fn in_geometry_with_predicate(
+        &self,
+        rtxn: &RoTxn,
+        geometry: &Geometry,
+        predicate: Predicate,
+    ) -> Result<RoaringBitmap> {
+        let mut polygons = Vec::new();
+        collect_polygons(geometry, &mut polygons)?;
+        let polygon = MultiPolygon::new(polygons);
+
+        match predicate {
+            Predicate::Intersects | Predicate::Within => {
+                self.in_multi_polygon_with_inspector(rtxn, &polygon, predicate, None, &mut |_| ())
+            }
+            Predicate::Contains | Predicate::Covers => {
+                self.covering_multi_polygon(rtxn, &polygon, predicate)
             }
+            Predicate::Disjoint => {
+                let intersecting = self.in_multi_polygon_with_inspector(
+                    rtxn,
+                    &polygon,
+                    Predicate::Intersects,
+                    None,
+                    &mut |_| (),
+                )?;
+                let mut ret = RoaringBitmap::new();
+                for entry in self
+                    .item_db()
+                    .remap_data_type::<DecodeIgnore>()
+                    .iter(rtxn)?
+                {
+                    let (item, ()) = entry?;
+                    ret.insert(item);
+                }
+                Ok(ret - intersecting)
+            }
+        }
+    }
 
-            let cell_polygon = MultiPolygon::from(cell);
+    /// Return all the items that intersects or are contained in the specified geojson.
+    /// The geojson must have an area, see [`Self::in_geometry_with_inspector`].
+    pub fn in_geojson(&self, rtxn: &RoTxn, geojson: &GeoJson) -> Result<RoaringBitmap> {
+        let geometry = Geometry::try_from(geojson.clone()).map_err(Box::new)?;
+        self.in_geometry(rtxn, &geometry)
+    }
 
-            let relate = polygon.relate(&cell_polygon);
+    /// Only supports the [`Predicate::Intersects`] and [`Predicate::Within`] predicates.
+    fn in_multi_polygon_with_inspector(
+        &self,
+        rtxn: &RoTxn,
+        polygon: &MultiPolygon,
+        predicate: Predicate,
+        filter: Option<&Filter>,
+        inspector: impl FnMut((FilteringStep, CellIndex)),
+    ) -> Result<RoaringBitmap> {
+        let shape = PolygonShape::new(polygon, predicate);
+        // Even if a cell is contained in the shape, its items can go outside of the shape
+        let contained_cells_are_certain = predicate != Predicate::Within;
+        self.explore_with_inspector(rtxn, &shape, contained_cells_are_certain, filter, inspector)
+    }
 
-            if relate.is_contains() {
-                (inspector)((FilteringStep::Returned, cell));
-                if let Some(cell_items) = cell_items {
-                    // If we're not too large it means we'll cover the whole shape again at the next resolution.
-                    // Since we already know that our children are guarenteed to be entirely contained in the shape
-                    // don't have to check them again.
-                    if let Some(next_res) = cell.resolution().succ() {
-                        already_explored.extend(cell.children(next_res));
-                    }
-                    ret |= cell_items;
-                }
-                if let Some(belly_items) = belly_items {
-                    ret |= belly_items;
-                }
-            } else if relate.is_intersects() {
-                if let Some(cell_items) = cell_items {
-                    let resolution = cell.resolution();
-                    if cell_items.len() < self.threshold || resolution == Resolution::Fifteen {
-                        (inspector)((FilteringStep::RequireDoubleCheck, cell));
-                        double_check |= cell_items;
-                    } else if already_tiled == Some(resolution) {
-                        // We already tiled the whole shape at a previous step, no need to do it again
-                        continue;
-                    } else {
-                        let next_res = resolution.succ().unwrap();
-                        (inspector)((FilteringStep::DeepDive, cell));
-                        let mut tiler = TilerBuilder::new(next_res)
-                            .containment_mode(ContainmentMode::Covers)
-                            .build();
-                        if too_large {
-                            tiler.add_batch(cell_polygon.into_iter())?;
-                        } else {
-                            already_tiled = Some(resolution);
-                            tiler.add(polygon.clone())?;
-                        }
+    /// Return all the items that intersects or are contained in the bounding box.
+    /// This is much faster than calling [`Self::in_shape`] with the polygon of the bounding box.
+    ///
+    /// Since a `Rect` cannot cross the antimeridian, see [`Self::in_bbox_from_corners`] for this case.
+    pub fn in_bbox(&self, rtxn: &RoTxn, bbox: Rect) -> Result<RoaringBitmap> {
+        self.in_bbox_from_corners(rtxn, bbox.min(), bbox.max())
+    }
 
-                        let mut cell_number = 0;
+    /// Return all the items that intersects or are contained in the bounding box made of the two corners.
+    /// If the longitude of the south west corner is greater than the one of the north east corner,
+    /// the bounding box crosses the antimeridian.
+    pub fn in_bbox_from_corners(
+        &self,
+        rtxn: &RoTxn,
+        south_west: Coord,
+        north_east: Coord,
+    ) -> Result<RoaringBitmap> {
+        let shape = BboxShape::new(south_west, north_east);
+        self.explore_with_inspector(rtxn, &shape, true, None, &mut |_| ())
+    }
 
-                        for cell in tiler.into_coverage() {
-                            if !already_explored.contains(&cell) {
-                                to_explore.push_back(cell);
-                            }
-                            cell_number += 1;
-                        }
+    /// Return all the items matching the shape.
+    /// If `contained_cells_are_certain` is `false`, the items of the cells contained in the shape are double checked.
+    /// The items rejected by the `filter` are removed before being double checked.
+    fn explore_with_inspector(
+        &self,
+        rtxn: &RoTxn,
+        shape: &impl QueryShape,
+        contained_cells_are_certain: bool,
+        filter: Option<&Filter>,
+        mut inspector: impl FnMut((FilteringStep, CellIndex)),
+    ) -> Result<RoaringBitmap> {
+        let mut exploration = Exploration::new(shape)?;
+        while exploration.explore_next_cell(self, rtxn, filter, &mut inspector)? {}
+        let Exploration {
+            certain: mut ret,
+            mut double_check,
+            ..
+        } = exploration;
 
-                        if cell_number > BECOME_TOO_LARGE {
-                            too_large = true;
-                        }
-                    }
-                }
-                if let Some(belly_items) = belly_items {
-                    ret |= belly_items;
-                }
-            } else {
-                // else: we can ignore the cell, it's not part of our shape
-                (inspector)((FilteringStep::OutsideOfShape, cell));
-            }
+        if !contained_cells_are_certain {
+            double_check |= std::mem::take(&mut ret);
         }
 
         // Since we have overlap some items may have been definitely validated somewhere but were also included as something to double check
         double_check -= &ret;
 
+        if let Some(filter) = filter {
+            filter.apply(&mut ret);
+            filter.apply(&mut double_check);
+        }
+
+        for item in double_check {
+            let item_shape = self.item_db().get(rtxn, &item)?.unwrap();
+            if shape.matches(item_shape) {
+                ret.insert(item);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Return a lazy iterator over the items that intersects or are contained in the specified polygon.
+    /// The items of the cells contained in the polygon are returned first, then the items requiring
+    /// a double check are returned one by one as they're checked.
+    /// Nothing is explored or checked until the iterator is consumed, see [`Self::in_shape_with_limit`].
+    pub fn in_shape_iter<'t>(
+        &'t self,
+        rtxn: &'t RoTxn<'t>,
+        polygon: &Polygon,
+    ) -> Result<QueryCursor<'t>> {
+        self.query_cursor(rtxn, polygon, None)
+    }
+
+    /// Return a lazy iterator over the items that intersects or are contained in the specified polygon and are accepted by the filter.
+    /// The filter is applied before the expensive check of the shape of the items, see [`Self::in_shape_iter`] and [`Filter`].
+    pub fn in_shape_iter_with_filter<'t>(
+        &'t self,
+        rtxn: &'t RoTxn<'t>,
+        polygon: &Polygon,
+        filter: &Filter<'t>,
+    ) -> Result<QueryCursor<'t>> {
+        self.query_cursor(rtxn, polygon, Some(*filter))
+    }
+
+    fn query_cursor<'t>(
+        &'t self,
+        rtxn: &'t RoTxn<'t>,
+        polygon: &Polygon,
+        filter: Option<Filter<'t>>,
+    ) -> Result<QueryCursor<'t>> {
+        let polygon = MultiPolygon::new(vec![polygon.clone()]);
+        let shape = PolygonShape::new(&polygon, Predicate::Intersects);
+        Ok(QueryCursor {
+            cellulite: self,
+            rtxn,
+            exploration: Exploration::new(shape)?,
+            filter,
+            returned: RoaringBitmap::new(),
+        })
+    }
+
+    /// Return at most `limit` items that intersects or are contained in the specified polygon.
+    /// The exploration of the cells and the double check of the items stops as soon as enough items have been found.
+    pub fn in_shape_with_limit(
+        &self,
+        rtxn: &RoTxn,
+        polygon: &Polygon,
+        limit: usize,
+    ) -> Result<RoaringBitmap> {
+        self.in_shape_iter(rtxn, polygon)?.take(limit).collect()
+    }
+
+    /// Estimate the number of items that intersects or are contained in the specified polygon.
+    /// Only the cells are explored, the items of the cells on the border of the polygon are counted
+    /// in the upper bound of the estimate without checking their shape.
+    /// The exact count requires checking these items, use the length of [`Self::in_shape`] for it.
+    pub fn estimate_count_in_shape(
+        &self,
+        rtxn: &RoTxn,
+        polygon: &Polygon,
+    ) -> Result<CountEstimate> {
+        let polygon = MultiPolygon::new(vec![polygon.clone()]);
+        let shape = PolygonShape::new(&polygon, Predicate::Intersects);
+        let mut exploration = Exploration::new(shape)?;
+        while exploration.explore_next_cell(self, rtxn, None, &mut |_| ())? {}
+
+        Ok(CountEstimate {
+            lower_bound: exploration.certain.len(),
+            upper_bound: exploration.certain.union_len(&exploration.double_check),
+        })
+    }
+
+    /// Return the items that contains or covers the whole shape.
+    // An item that contains the shape must contain all of its points, thus we only have to follow the cells of one point of the shape.
+    // The belly items of a cell that contains the whole shape are guaranteed to contain the shape as well.
+    fn covering_multi_polygon(
+        &self,
+        rtxn: &RoTxn,
+        polygon: &MultiPolygon,
+        predicate: Predicate,
+    ) -> Result<RoaringBitmap> {
+        let Some(coord) = polygon.coords_iter().next() else {
+            return Ok(RoaringBitmap::new());
+        };
+        let lat_lng = LatLng::new(coord.y, coord.x)?;
+
+        let (mut ret, double_check) = self.items_on_path(rtxn, lat_lng, |cell| {
+            MultiPolygon::from(cell).relate(polygon).is_contains()
+        })?;
+
         for item in double_check {
-            let shape = self.item_db().get(rtxn, &item)?.unwrap();
-            if shape.any_relation(&polygon).any_relation() {
+            let shape = self
+                .item_db()
+                .get(rtxn, &item)?
+                .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
+            if predicate.matches(shape, polygon) {
                 ret.insert(item);
             }
         }
@@ -136,6 +332,167 @@ impl Cellulite {
         Ok(ret)
     }
 
+    /// Return all the items that contains or touches the point, typically the polygons a point is in.
+    /// This is much cheaper than calling [`Self::in_shape`] with a tiny polygon around the point.
+    pub fn containing(&self, rtxn: &RoTxn, point: Point) -> Result<RoaringBitmap> {
+        let lat_lng = LatLng::new(point.y(), point.x())?;
+        // A belly item contains its cell and thus the point
+        let (mut ret, double_check) = self.items_on_path(rtxn, lat_lng, |_| true)?;
+
+        for item in double_check {
+            let shape = self
+                .item_db()
+                .get(rtxn, &item)?
+                .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
+            if crate::zerometry::to_geometry(shape).intersects(&point) {
+                ret.insert(item);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Follow the cells containing the point from the resolution zero down to the first leaf.
+    /// Returns the belly items of the cells for which `belly_is_certain` returns `true`,
+    /// and all the other items that needs to be double checked.
+    // 1. At every resolution, retrieve the cell containing the point
+    // 2. Its belly items are either certain or must be double-checked
+    // 3. Stop at the first leaf and double-check all of its items
+    fn items_on_path(
+        &self,
+        rtxn: &RoTxn,
+        lat_lng: LatLng,
+        belly_is_certain: impl Fn(CellIndex) -> bool,
+    ) -> Result<(RoaringBitmap, RoaringBitmap)> {
+        let mut certain = RoaringBitmap::new();
+        let mut double_check = RoaringBitmap::new();
+
+        for resolution in Resolution::range(Resolution::Zero, Resolution::Fifteen) {
+            let cell = lat_lng.to_cell(resolution);
+            let (cell_items, belly_items) =
+                crate::keys::retrieve_cell_and_belly(rtxn, &self.cell_db(), cell)?;
+
+            if let Some(belly_items) = belly_items {
+                if belly_is_certain(cell) {
+                    certain |= belly_items;
+                } else {
+                    double_check |= belly_items;
+                }
+            }
+
+            match cell_items {
+                Some(cell_items) if self.settings.must_split(cell_items.len(), resolution) => (),
+                Some(cell_items) => {
+                    double_check |= cell_items;
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        double_check -= &certain;
+        Ok((certain, double_check))
+    }
+
+    /// Return the number of items intersecting each cell at `resolution` covering the area, typically to draw a heatmap.
+    /// The cells without any item are omitted.
+    /// The counts come from the cells stored in the database, the shape of the items are only checked
+    /// when the database doesn't go as deep as the requested resolution.
+    pub fn density(
+        &self,
+        rtxn: &RoTxn,
+        area: &Polygon,
+        resolution: Resolution,
+    ) -> Result<BTreeMap<CellIndex, u64>> {
+        let area = MultiPolygon::new(vec![area.clone()]);
+        let shape = PolygonShape::new(&area, Predicate::Intersects);
+        let targets: Vec<(CellIndex, MultiPolygon)> = shape
+            .cover(resolution)?
+            .into_iter()
+            .map(|cell| (cell, MultiPolygon::from(cell)))
+            .collect();
+        let mut items = vec![RoaringBitmap::new(); targets.len()];
+
+        // The children of a cell are not contained in it, a target can intersect the children of
+        // the neighbours of its parent. The descendants of a cell stay close to it though.
+        let mut to_explore: Vec<(CellIndex, Vec<usize>)> = Vec::new();
+        let mut base_cells: HashMap<CellIndex, Vec<usize>> = HashMap::new();
+        for (i, (target, _)) in targets.iter().enumerate() {
+            // safe to unwrap because every resolution is greater or equal to zero
+            for cell in target
+                .parent(Resolution::Zero)
+                .unwrap()
+                .grid_disk::<Vec<_>>(1)
+            {
+                base_cells.entry(cell).or_default().push(i);
+            }
+        }
+        to_explore.extend(base_cells);
+        // The children of the neighbouring cells overlap, a cell is only explored once for each target
+        let mut explored: HashMap<CellIndex, HashSet<usize>> = HashMap::new();
+
+        // 1. Dive into the cells stored in the database while they intersect any target
+        // 2. The belly items of a cell intersect all the targets intersecting the cell
+        // 3. Stop at the targets themselves or at the first leaf and double-check its items
+        while let Some((cell, candidates)) = to_explore.pop() {
+            let cell_shape = MultiPolygon::from(cell);
+            let already_explored = explored.entry(cell).or_default();
+            let candidates: Vec<usize> = candidates
+                .into_iter()
+                .filter(|&i| already_explored.insert(i) && targets[i].1.intersects(&cell_shape))
+                .collect();
+            if candidates.is_empty() {
+                continue;
+            }
+            let (cell_items, belly_items) =
+                crate::keys::retrieve_cell_and_belly(rtxn, &self.cell_db(), cell)?;
+            if let Some(belly_items) = belly_items {
+                for &i in &candidates {
+                    items[i] |= &belly_items;
+                }
+            }
+            let Some(cell_items) = cell_items else {
+                continue;
+            };
+
+            if cell.resolution() == resolution {
+                // The cells of the same resolution don't overlap, only the target itself matters
+                if let Some(&i) = candidates.iter().find(|&&i| targets[i].0 == cell) {
+                    items[i] |= cell_items;
+                }
+            } else if self
+                .settings
+                .must_split(cell_items.len(), cell.resolution())
+            {
+                // safe to unwrap because we're not at the last resolution yet
+                for child in get_children_cells(cell)?.unwrap() {
+                    to_explore.push((child, candidates.clone()));
+                }
+            } else {
+                for item in cell_items {
+                    let shape = self
+                        .item_db()
+                        .get(rtxn, &item)?
+                        .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
+                    for &i in &candidates {
+                        if !items[i].contains(item)
+                            && shape.any_relation(&targets[i].1).any_relation()
+                        {
+                            items[i].insert(item);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(targets
+            .into_iter()
+            .zip(items)
+            .filter(|(_, items)| !items.is_empty())
+            .map(|((cell, _), items)| (cell, items.len()))
+            .collect())
+    }
+
     /// Retrieve all items intersecting a circle with a given center and radius, according to the Haversine model.
     /// This is approximate. It may miss items that are in the circle, but it will never return items that are not in the circle.
     /// The resolution parameter controls the number of points used to approximate the circle.
@@ -176,6 +533,491 @@ impl Cellulite {
 
         self.in_shape_with_inspector(rtxn, &polygon, inspector)
     }
+
+    /// Retrieve all items intersecting a circle with a given center and radius in meters, according to the Haversine model.
+    /// Unlike [`Self::in_circle`] this is exact, all the items at less than `radius` meters of the center are returned
+    /// and none of the items further away are returned.
+    pub fn in_circle_exact(
+        &self,
+        rtxn: &RoTxn,
+        center: Point,
+        radius: f64,
+    ) -> Result<RoaringBitmap> {
+        self.in_circle_exact_with_inspector(rtxn, center, radius, &Haversine, &mut |_| ())
+    }
+
+    /// Retrieve all items intersecting a circle with a given center and radius.
+    /// The cells and the items are checked against the real distance given by the `measure`.
+    pub fn in_circle_exact_with_inspector<Measure: Distance<f64, Point, Point>>(
+        &self,
+        rtxn: &RoTxn,
+        center: Point,
+        radius: f64,
+        measure: &Measure,
+        inspector: impl FnMut((FilteringStep, CellIndex)),
+    ) -> Result<RoaringBitmap> {
+        let shape = CircleShape::new(center, radius, measure);
+        self.explore_with_inspector(rtxn, &shape, true, None, inspector)
+    }
+
+    /// Retrieve all the items at less than `distance` meters of the geometry, according to the Haversine model.
+    /// With a line string it returns all the items along a road for example.
+    pub fn within_distance(
+        &self,
+        rtxn: &RoTxn,
+        geometry: &Geometry,
+        distance: f64,
+    ) -> Result<RoaringBitmap> {
+        self.within_distance_with_inspector(rtxn, geometry, distance, &mut |_| ())
+    }
+
+    /// Retrieve all the items at less than `distance` meters of the geometry.
+    /// The `inspector` lets you see how the search was made internally.
+    pub fn within_distance_with_inspector(
+        &self,
+        rtxn: &RoTxn,
+        geometry: &Geometry,
+        distance: f64,
+        inspector: impl FnMut((FilteringStep, CellIndex)),
+    ) -> Result<RoaringBitmap> {
+        let shape = CorridorShape::new(geometry, distance);
+        self.explore_with_inspector(rtxn, &shape, true, None, inspector)
+    }
+
+    /// Return the `k` items closest to the point, sorted by their Haversine distance in meters.
+    /// The distance of an item is the distance between the point and the closest point of the item,
+    /// it's `0` if the point is contained in the item.
+    // The strategy to retrieve the nearest items is to:
+    // 1. Explore the cells@res0 by rings around the cell containing the point
+    // 2. Always explore the cell that could contain the closest item first
+    //  2.1 If the cell is a leaf => compute the distance of all its items
+    //  2.2 Otherwise, queue its children cells
+    // 3. Stop when neither the queued cells nor the next ring can contain an item closer than the k-th one
+    pub fn nearest(&self, rtxn: &RoTxn, point: Point, k: usize) -> Result<Vec<(ItemId, f64)>> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let origin = LatLng::new(point.y(), point.x())?.to_cell(Resolution::Zero);
+
+        // max-heap of the best items found so far, the k-th closest item is on top
+        let mut best: BinaryHeap<(OrderedFloat<f64>, ItemId)> = BinaryHeap::with_capacity(k + 1);
+        let mut already_computed = RoaringBitmap::new();
+        let mut to_explore: BinaryHeap<Reverse<(OrderedFloat<f64>, CellIndex)>> = BinaryHeap::new();
+        let mut already_explored: HashSet<CellIndex> = HashSet::new();
+
+        let mut ring = 0;
+        let mut next_ring = base_cells_ring(origin, ring, &mut already_explored, point);
+
+        loop {
+            let kth_distance = if best.len() == k {
+                best.peek()
+                    .map_or(f64::INFINITY, |(distance, _)| distance.0)
+            } else {
+                f64::INFINITY
+            };
+            let ring_bound = next_ring
+                .iter()
+                .map(|Reverse((distance, _))| distance.0)
+                .fold(f64::INFINITY, f64::min);
+            let cell_bound = to_explore
+                .peek()
+                .map_or(f64::INFINITY, |Reverse((distance, _))| distance.0);
+
+            if next_ring.is_empty() && to_explore.is_empty() {
+                break;
+            }
+            if ring_bound.min(cell_bound) > kth_distance {
+                break;
+            }
+
+            if ring_bound <= cell_bound {
+                to_explore.extend(next_ring.drain(..));
+                ring += 1;
+                next_ring = base_cells_ring(origin, ring, &mut already_explored, point);
+                continue;
+            }
+
+            let Some(Reverse((_, cell))) = to_explore.pop() else {
+                break;
+            };
+            let (cell_items, belly_items) =
+                crate::keys::retrieve_cell_and_belly(rtxn, &self.cell_db(), cell)?;
+
+            let mut candidates = belly_items.unwrap_or_default();
+            if let Some(cell_items) = cell_items {
+                if !self
+                    .settings
+                    .must_split(cell_items.len(), cell.resolution())
+                {
+                    candidates |= cell_items;
+                } else if let Some(children) = get_children_cells(cell)? {
+                    for child in children {
+                        if already_explored.insert(child) {
+                            let distance = cell_lower_bound_distance(&Haversine, child, point);
+                            to_explore.push(Reverse((OrderedFloat(distance), child)));
+                        }
+                    }
+                }
+            }
+            candidates -= &already_computed;
+
+            for item in candidates {
+                already_computed.insert(item);
+                let shape = self
+                    .item_db()
+                    .get(rtxn, &item)?
+                    .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
+                let distance =
+                    distance_to_geometry(&Haversine, &crate::zerometry::to_geometry(shape), point);
+                best.push((OrderedFloat(distance), item));
+                if best.len() > k {
+                    best.pop();
+                }
+            }
+        }
+
+        Ok(best
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(distance, item)| (item, distance.0))
+            .collect())
+    }
+}
+
+/// The state of the exploration of the cells covering a shape.
+/// The cells are explored one at a time so the exploration can be stopped early, see [`QueryCursor`].
+// The strategy to retrieve the points in a shape is to:
+// 1. Retrieve all the cell@res0 that contains the shape
+// 2. Iterate over these cells
+//  2.1.If a cell fit entirely *inside* the shape, add all its items to the result
+//  2.2 Otherwise:
+//   - If the cell is a leaf => iterate over all of its point and add the one that fits in the shape to the result
+//   - Otherwise, increase the precision and iterate on the range of cells => repeat step 2
+struct Exploration<S> {
+    shape: S,
+    to_explore: VecDeque<CellIndex>,
+    already_explored: HashSet<CellIndex>,
+    too_large: bool,
+    already_tiled: Option<Resolution>,
+    /// The items of the cells contained in the shape and of the belly cells.
+    certain: RoaringBitmap,
+    /// The items of the cells intersecting the shape, they must be checked against the shape.
+    double_check: RoaringBitmap,
+}
+
+impl<S: QueryShape> Exploration<S> {
+    fn new(shape: S) -> Result<Self> {
+        let to_explore: VecDeque<_> = shape.cover(Resolution::Zero)?.into();
+        Ok(Self {
+            already_explored: HashSet::with_capacity(to_explore.len()),
+            to_explore,
+            shape,
+            too_large: false,
+            already_tiled: None,
+            certain: RoaringBitmap::new(),
+            double_check: RoaringBitmap::new(),
+        })
+    }
+
+    /// Explore the next cell and dispatch its items between the certain and double check bitmaps.
+    /// Returns `false` once there is no cell left to explore.
+    fn explore_next_cell(
+        &mut self,
+        cellulite: &Cellulite,
+        rtxn: &RoTxn,
+        filter: Option<&Filter>,
+        inspector: &mut impl FnMut((FilteringStep, CellIndex)),
+    ) -> Result<bool> {
+        // Roughly equivalent to the number of children we would have in three cells
+        const BECOME_TOO_LARGE: usize = 60;
+
+        let Some(cell) = self.to_explore.pop_front() else {
+            return Ok(false);
+        };
+        if !self.already_explored.insert(cell) {
+            return Ok(true);
+        }
+
+        let (cell_items, belly_items) =
+            crate::keys::retrieve_cell_and_belly(rtxn, &cellulite.cell_db(), cell)?;
+
+        if cell_items.is_none() && belly_items.is_none() {
+            (inspector)((FilteringStep::NotPresentInDB, cell));
+            return Ok(true);
+        }
+
+        match self.shape.relation_with_cell(cell) {
+            CellRelation::Contained => {
+                (inspector)((FilteringStep::Returned, cell));
+                if let Some(cell_items) = cell_items {
+                    // If we're not too large it means we'll cover the whole shape again at the next resolution.
+                    // Since we already know that our children are guarenteed to be entirely contained in the shape
+                    // don't have to check them again.
+                    if let Some(next_res) = cell.resolution().succ() {
+                        self.already_explored.extend(cell.children(next_res));
+                    }
+                    self.certain |= cell_items;
+                }
+                if let Some(belly_items) = belly_items {
+                    self.certain |= belly_items;
+                }
+            }
+            CellRelation::Intersects => {
+                // If none of the items of the cell can be returned there is no need to go deeper
+                let cell_items = cell_items
+                    .filter(|items| filter.is_none_or(|filter| filter.may_contain(items)));
+                if let Some(cell_items) = cell_items {
+                    let resolution = cell.resolution();
+                    if !cellulite.settings.must_split(cell_items.len(), resolution) {
+                        (inspector)((FilteringStep::RequireDoubleCheck, cell));
+                        self.double_check |= cell_items;
+                    } else if self.already_tiled == Some(resolution) {
+                        // We already tiled the whole shape at a previous step, no need to do it again
+                        return Ok(true);
+                    } else {
+                        let next_res = resolution.succ().unwrap();
+                        (inspector)((FilteringStep::DeepDive, cell));
+                        let cells = if self.too_large {
+                            cover_cell(cell, next_res)?
+                        } else {
+                            self.already_tiled = Some(resolution);
+                            self.shape.cover(next_res)?
+                        };
+
+                        if cells.len() > BECOME_TOO_LARGE {
+                            self.too_large = true;
+                        }
+
+                        for cell in cells {
+                            if !self.already_explored.contains(&cell) {
+                                self.to_explore.push_back(cell);
+                            }
+                        }
+                    }
+                }
+                if let Some(belly_items) = belly_items {
+                    self.certain |= belly_items;
+                }
+            }
+            CellRelation::Disjoint => {
+                // we can ignore the cell, it's not part of our shape
+                (inspector)((FilteringStep::OutsideOfShape, cell));
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// A lazy iterator over the items matching a query, see [`Cellulite::in_shape_iter`].
+/// Every item is returned only once, in no particular order.
+pub struct QueryCursor<'t> {
+    cellulite: &'t Cellulite,
+    rtxn: &'t RoTxn<'t>,
+    exploration: Exploration<PolygonShape>,
+    filter: Option<Filter<'t>>,
+    /// The items that have already been returned.
+    returned: RoaringBitmap,
+}
+
+impl QueryCursor<'_> {
+    fn next_item(&mut self) -> Result<Option<ItemId>> {
+        loop {
+            // 1. Return the items that are guaranteed to match as soon as they are found
+            if let Some(item) = self.exploration.certain.min() {
+                self.exploration.certain.remove(item);
+                if self.accepts(item) && self.returned.insert(item) {
+                    return Ok(Some(item));
+                }
+                continue;
+            }
+
+            // 2. Go one cell deeper
+            if self.exploration.explore_next_cell(
+                self.cellulite,
+                self.rtxn,
+                self.filter.as_ref(),
+                &mut |_| (),
+            )? {
+                continue;
+            }
+
+            // 3. Once all the cells have been explored, check the remaining items one by one
+            let Some(item) = self.exploration.double_check.min() else {
+                return Ok(None);
+            };
+            self.exploration.double_check.remove(item);
+            if self.returned.contains(item) || !self.accepts(item) {
+                continue;
+            }
+            let shape = self
+                .cellulite
+                .item_db()
+                .get(self.rtxn, &item)?
+                .ok_or_else(|| Error::InternalDocIdMissing(item, pos!()))?;
+            if self.exploration.shape.matches(shape) {
+                self.returned.insert(item);
+                return Ok(Some(item));
+            }
+        }
+    }
+}
+
+impl QueryCursor<'_> {
+    fn accepts(&self, item: ItemId) -> bool {
+        self.filter.is_none_or(|filter| filter.contains(item))
+    }
+}
+
+impl Iterator for QueryCursor<'_> {
+    type Item = Result<ItemId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().transpose()
+    }
+}
+
+/// Retrieve all the polygons contained in a geometry. Returns an error if the geometry doesn't have an area.
+fn collect_polygons(geometry: &Geometry, polygons: &mut Vec<Polygon>) -> Result<()> {
+    match geometry {
+        Geometry::Polygon(polygon) => polygons.push(polygon.clone()),
+        Geometry::MultiPolygon(multi_polygon) => polygons.extend(multi_polygon.iter().cloned()),
+        Geometry::Rect(rect) => polygons.push(rect.to_polygon()),
+        Geometry::Triangle(triangle) => polygons.push(triangle.to_polygon()),
+        Geometry::GeometryCollection(collection) => {
+            for geometry in collection.iter() {
+                collect_polygons(geometry, polygons)?;
+            }
+        }
+        Geometry::Point(_) => return Err(Error::UnsupportedQueryGeometry("Point")),
+        Geometry::Line(_) => return Err(Error::UnsupportedQueryGeometry("Line")),
+        Geometry::LineString(_) => return Err(Error::UnsupportedQueryGeometry("LineString")),
+        Geometry::MultiPoint(_) => return Err(Error::UnsupportedQueryGeometry("MultiPoint")),
+        Geometry::MultiLineString(_) => {
+            return Err(Error::UnsupportedQueryGeometry("MultiLineString"));
+        }
+    }
+    Ok(())
+}
+
+/// Return the cells@res0 that are exactly at `ring` of distance of the origin and that were never explored before.
+fn base_cells_ring(
+    origin: CellIndex,
+    ring: u32,
+    already_explored: &mut HashSet<CellIndex>,
+    point: Point,
+) -> Vec<Reverse<(OrderedFloat<f64>, CellIndex)>> {
+    origin
+        .grid_disk::<Vec<_>>(ring)
+        .into_iter()
+        .filter(|cell| already_explored.insert(*cell))
+        .map(|cell| {
+            Reverse((
+                OrderedFloat(cell_lower_bound_distance(&Haversine, cell, point)),
+                cell,
+            ))
+        })
+        .collect()
+}
+
+/// The spatial relation an item must have with the shape of a query to be returned.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// The item intersects or is contained in the shape.
+    #[default]
+    Intersects,
+    /// The item is entirely contained in the shape.
+    Within,
+    /// The item contains the whole shape without touching its boundary.
+    Contains,
+    /// The item contains the whole shape, their boundaries can touch.
+    Covers,
+    /// The item doesn't touch the shape at all.
+    Disjoint,
+}
+
+impl Predicate {
+    pub(crate) fn matches(&self, shape: Zerometry, polygon: &MultiPolygon) -> bool {
+        match self {
+            Predicate::Intersects => shape.any_relation(polygon).any_relation(),
+            Predicate::Disjoint => !shape.any_relation(polygon).any_relation(),
+            Predicate::Within => shape
+                .relation(polygon, InputRelation::all())
+                .contained
+                .unwrap_or_default(),
+            Predicate::Contains => shape
+                .relation(polygon, InputRelation::all())
+                .strict_contains
+                .unwrap_or_default(),
+            Predicate::Covers => shape
+                .relation(polygon, InputRelation::all())
+                .contains
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// An estimation of the number of items matching a query, see [`Cellulite::estimate_count_in_shape`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CountEstimate {
+    /// The number of items guaranteed to match the query.
+    pub lower_bound: u64,
+    /// The number of items that may match the query.
+    pub upper_bound: u64,
+}
+
+impl CountEstimate {
+    /// Return the middle of the bounds, the exact count is at most [`Self::error`] away from it.
+    pub fn estimate(&self) -> u64 {
+        self.lower_bound + self.error()
+    }
+
+    /// Return the maximum difference between [`Self::estimate`] and the exact count.
+    pub fn error(&self) -> u64 {
+        (self.upper_bound - self.lower_bound).div_ceil(2)
+    }
+
+    /// Return `true` if the estimate is guaranteed to be the exact count.
+    pub fn is_exact(&self) -> bool {
+        self.lower_bound == self.upper_bound
+    }
+}
+
+/// Restricts the items a query can return, typically with the result of a search on the properties of the items.
+/// The filter is applied before the shapes of the items are retrieved and checked against the query.
+#[derive(Copy, Clone)]
+pub enum Filter<'a> {
+    /// Only the items contained in the bitmap can be returned.
+    Allowlist(&'a RoaringBitmap),
+    /// Only the items for which the closure returns `true` can be returned.
+    Closure(&'a dyn Fn(ItemId) -> bool),
+}
+
+impl Filter<'_> {
+    /// Return `false` if none of the items can be returned.
+    /// Always returns `true` for a closure since it would require to call it on all the items.
+    pub(crate) fn may_contain(&self, items: &RoaringBitmap) -> bool {
+        match self {
+            Filter::Allowlist(allowlist) => !allowlist.is_disjoint(items),
+            Filter::Closure(_) => true,
+        }
+    }
+
+    /// Return `true` if the item can be returned.
+    pub(crate) fn contains(&self, item: ItemId) -> bool {
+        match self {
+            Filter::Allowlist(allowlist) => allowlist.contains(item),
+            Filter::Closure(accept) => accept(item),
+        }
+    }
+
+    /// Remove the items that cannot be returned from the bitmap.
+    pub(crate) fn apply(&self, items: &mut RoaringBitmap) {
+        match self {
+            Filter::Allowlist(allowlist) => *items &= *allowlist,
+            Filter::Closure(accept) => *items = items.iter().filter(|item| accept(*item)).collect(),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -0,0 +1,433 @@
+use geo::{
+    BoundingRect, Closest, Coord, CoordsIter, Densify, Distance, Euclidean, Geometry,
+    GeometryCollection, Haversine, HaversineClosestPoint, Intersects, LineString, MultiLineString,
+    MultiPoint, MultiPolygon, Point, Polygon, Rect, Relate,
+};
+use h3o::{
+    CellIndex, LatLng, Resolution,
+    geom::{ContainmentMode, TilerBuilder},
+};
+use zerometry::{RelationBetweenShapes, Zerometry};
+
+use crate::{Result, reader::Predicate};
+
+/// The relation between a cell and the shape of a query.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum CellRelation {
+    /// The cell is entirely contained in the shape.
+    Contained,
+    /// The cell is partially in the shape.
+    Intersects,
+    /// The cell is entirely outside of the shape.
+    Disjoint,
+}
+
+/// A shape that can be used to explore the cells of the database.
+pub(crate) trait QueryShape {
+    /// Return the cells at `resolution` covering the whole shape.
+    fn cover(&self, resolution: Resolution) -> Result<Vec<CellIndex>>;
+
+    /// Return the relation between the cell and the shape.
+    fn relation_with_cell(&self, cell: CellIndex) -> CellRelation;
+
+    /// Return `true` if the item must be returned by the query.
+    fn matches(&self, shape: Zerometry) -> bool;
+}
+
+impl<S: QueryShape + ?Sized> QueryShape for &S {
+    fn cover(&self, resolution: Resolution) -> Result<Vec<CellIndex>> {
+        (**self).cover(resolution)
+    }
+
+    fn relation_with_cell(&self, cell: CellIndex) -> CellRelation {
+        (**self).relation_with_cell(cell)
+    }
+
+    fn matches(&self, shape: Zerometry) -> bool {
+        (**self).matches(shape)
+    }
+}
+
+/// Return the cells at `resolution` covering the cell.
+pub(crate) fn cover_cell(cell: CellIndex, resolution: Resolution) -> Result<Vec<CellIndex>> {
+    let mut tiler = TilerBuilder::new(resolution)
+        .containment_mode(ContainmentMode::Covers)
+        .build();
+    tiler.add_batch(MultiPolygon::from(cell))?;
+    Ok(tiler.into_coverage().collect())
+}
+
+/// A multi-polygon queried with a predicate. Only [`Predicate::Intersects`] and [`Predicate::Within`] can be explored by cells.
+pub(crate) struct PolygonShape {
+    polygon: MultiPolygon,
+    predicate: Predicate,
+}
+
+impl PolygonShape {
+    pub fn new(polygon: &MultiPolygon, predicate: Predicate) -> Self {
+        Self {
+            polygon: Haversine.densify(polygon, 1_000.0),
+            predicate,
+        }
+    }
+}
+
+impl QueryShape for PolygonShape {
+    fn cover(&self, resolution: Resolution) -> Result<Vec<CellIndex>> {
+        let mut tiler = TilerBuilder::new(resolution)
+            .containment_mode(ContainmentMode::Covers)
+            .build();
+        tiler.add_batch(self.polygon.0.clone())?;
+        Ok(tiler.into_coverage().collect())
+    }
+
+    fn relation_with_cell(&self, cell: CellIndex) -> CellRelation {
+        let relate = self.polygon.relate(&MultiPolygon::from(cell));
+        if relate.is_contains() {
+            CellRelation::Contained
+        } else if relate.is_intersects() {
+            CellRelation::Intersects
+        } else {
+            CellRelation::Disjoint
+        }
+    }
+
+    fn matches(&self, shape: Zerometry) -> bool {
+        self.predicate.matches(shape, &self.polygon)
+    }
+}
+
+/// A bounding box, if it crosses the antimeridian it's split in two rects.
+/// The relation with the cells is computed with their bounding boxes which is much cheaper than a `relate`.
+pub(crate) struct BboxShape {
+    rects: Vec<Rect>,
+    polygon: MultiPolygon,
+    /// The polygon densified for the tiler, see [`BboxShape::new`].
+    densified: MultiPolygon,
+}
+
+impl BboxShape {
+    /// The bounding box crosses the antimeridian when the longitude of the south west corner
+    /// is greater than the one of the north east corner.
+    pub fn new(south_west: Coord, north_east: Coord) -> Self {
+        let rects = if south_west.x > north_east.x {
+            vec![
+                Rect::new(
+                    south_west,
+                    Coord {
+                        x: 180.0,
+                        y: north_east.y,
+                    },
+                ),
+                Rect::new(
+                    Coord {
+                        x: -180.0,
+                        y: south_west.y,
+                    },
+                    north_east,
+                ),
+            ]
+        } else {
+            vec![Rect::new(south_west, north_east)]
+        };
+        let polygon = rects.iter().map(|rect| rect.to_polygon()).collect();
+        // The tiler follows the geodesics between the vertices while the edges of the bbox follow the parallels,
+        // we add a vertex every ~1km like in the `PolygonShape` to not miss the cells on the border.
+        // The densification must be done in degrees to stay on the parallels.
+        let densified = Euclidean.densify(&polygon, 0.01);
+        Self {
+            rects,
+            polygon,
+            densified,
+        }
+    }
+}
+
+impl QueryShape for BboxShape {
+    fn cover(&self, resolution: Resolution) -> Result<Vec<CellIndex>> {
+        let mut tiler = TilerBuilder::new(resolution)
+            .containment_mode(ContainmentMode::Covers)
+            .build();
+        tiler.add_batch(self.densified.0.clone())?;
+        Ok(tiler.into_coverage().collect())
+    }
+
+    fn relation_with_cell(&self, cell: CellIndex) -> CellRelation {
+        // A cell crossing the antimeridian is split in multiple polygons
+        let cell_rects: Vec<Rect> = MultiPolygon::from(cell)
+            .iter()
+            .filter_map(|polygon| polygon.bounding_rect())
+            .collect();
+
+        let contained = cell_rects
+            .iter()
+            .all(|cell| self.rects.iter().any(|rect| rect_contains(rect, cell)));
+        if contained {
+            return CellRelation::Contained;
+        }
+        let intersects = cell_rects
+            .iter()
+            .any(|cell| self.rects.iter().any(|rect| rect_intersects(rect, cell)));
+        if intersects {
+            CellRelation::Intersects
+        } else {
+            CellRelation::Disjoint
+        }
+    }
+
+    fn matches(&self, shape: Zerometry) -> bool {
+        shape.any_relation(&self.polygon).any_relation()
+    }
+}
+
+fn rect_contains(container: &Rect, contained: &Rect) -> bool {
+    container.min().x <= contained.min().x
+        && container.min().y <= contained.min().y
+        && contained.max().x <= container.max().x
+        && contained.max().y <= container.max().y
+}
+
+fn rect_intersects(left: &Rect, right: &Rect) -> bool {
+    left.min().x <= right.max().x
+        && right.min().x <= left.max().x
+        && left.min().y <= right.max().y
+        && right.min().y <= left.max().y
+}
+
+/// A circle whose cells and items are checked against the real distance given by the measure.
+pub(crate) struct CircleShape<'a, M> {
+    center: Point,
+    radius: f64,
+    measure: &'a M,
+}
+
+impl<'a, M> CircleShape<'a, M> {
+    pub fn new(center: Point, radius: f64, measure: &'a M) -> Self {
+        Self {
+            center,
+            radius,
+            measure,
+        }
+    }
+}
+
+impl<M: Distance<f64, Point, Point>> QueryShape for CircleShape<'_, M> {
+    fn cover(&self, resolution: Resolution) -> Result<Vec<CellIndex>> {
+        let origin = LatLng::new(self.center.y(), self.center.x())?.to_cell(resolution);
+        // Two neighbour cells are roughly `sqrt(3) * edge` apart, we take a large margin for the distortions of the grid
+        let k = (self.radius / (resolution.edge_length_m() * 0.5)).ceil() as u32 + 1;
+        Ok(origin
+            .grid_disk::<Vec<_>>(k)
+            .into_iter()
+            .filter(|cell| self.relation_with_cell(*cell) != CellRelation::Disjoint)
+            .collect())
+    }
+
+    fn relation_with_cell(&self, cell: CellIndex) -> CellRelation {
+        if cell_lower_bound_distance(self.measure, cell, self.center) > self.radius {
+            CellRelation::Disjoint
+        } else if cell_is_within(self.measure, cell, self.center, self.radius) {
+            CellRelation::Contained
+        } else {
+            CellRelation::Intersects
+        }
+    }
+
+    fn matches(&self, shape: Zerometry) -> bool {
+        let shape = crate::zerometry::to_geometry(shape);
+        distance_to_geometry(self.measure, &shape, self.center) <= self.radius
+    }
+}
+
+/// All the points at less than `distance` meters of a geometry, according to the Haversine model.
+pub(crate) struct CorridorShape {
+    /// The whole geometry, used to compute the distances.
+    geometry: Geometry,
+    /// The same geometry split by kind, used to cover it with cells.
+    points: MultiPoint,
+    lines: MultiLineString,
+    polygons: MultiPolygon,
+    distance: f64,
+}
+
+impl CorridorShape {
+    pub fn new(geometry: &Geometry, distance: f64) -> Self {
+        let mut points = Vec::new();
+        let mut lines = Vec::new();
+        let mut polygons = Vec::new();
+        split_geometry(geometry, &mut points, &mut lines, &mut polygons);
+        Self {
+            geometry: geometry.clone(),
+            points: MultiPoint::new(points),
+            lines: MultiLineString::new(lines),
+            polygons: MultiPolygon::new(polygons),
+            distance,
+        }
+    }
+}
+
+impl QueryShape for CorridorShape {
+    fn cover(&self, resolution: Resolution) -> Result<Vec<CellIndex>> {
+        let edge = resolution.edge_length_m();
+        // Every cell near the geometry is close to one of its vertices once it has been densified at the size of the cells
+        let lines = Haversine.densify(&self.lines, edge);
+        let polygons = Haversine.densify(&self.polygons, edge);
+        let mut vertices = Vec::new();
+        for coord in self
+            .points
+            .coords_iter()
+            .chain(lines.coords_iter())
+            .chain(polygons.coords_iter())
+        {
+            vertices.push(LatLng::new(coord.y, coord.x)?.to_cell(resolution));
+        }
+        vertices.sort_unstable();
+        vertices.dedup();
+
+        // Two neighbour cells are roughly `sqrt(3) * edge` apart, we take a large margin for the distortions of the grid
+        let k = (self.distance / (edge * 0.5)).ceil() as u32 + 2;
+        let mut cells = Vec::new();
+        for vertex in vertices {
+            cells.extend(vertex.grid_disk::<Vec<_>>(k));
+        }
+        // The inside of the polygons is not around any vertex
+        if !self.polygons.0.is_empty() {
+            let mut tiler = TilerBuilder::new(resolution)
+                .containment_mode(ContainmentMode::Covers)
+                .build();
+            tiler.add_batch(self.polygons.0.clone())?;
+            cells.extend(tiler.into_coverage());
+        }
+        cells.sort_unstable();
+        cells.dedup();
+        cells.retain(|cell| self.relation_with_cell(*cell) != CellRelation::Disjoint);
+        Ok(cells)
+    }
+
+    fn relation_with_cell(&self, cell: CellIndex) -> CellRelation {
+        let (center, radius) = cell_center_and_radius(&Haversine, cell);
+        let distance = distance_to_geometry(&Haversine, &self.geometry, center);
+        if distance - radius > self.distance {
+            CellRelation::Disjoint
+        } else if distance + radius <= self.distance
+            || (!self.polygons.0.is_empty()
+                && self
+                    .polygons
+                    .relate(&MultiPolygon::from(cell))
+                    .is_contains())
+        {
+            CellRelation::Contained
+        } else {
+            CellRelation::Intersects
+        }
+    }
+
+    fn matches(&self, shape: Zerometry) -> bool {
+        let shape = crate::zerometry::to_geometry(shape);
+        geometries_distance(&shape, &self.geometry) <= self.distance
+    }
+}
+
+/// Split a geometry in its points, lines and polygons.
+fn split_geometry(
+    geometry: &Geometry,
+    points: &mut Vec<Point>,
+    lines: &mut Vec<LineString>,
+    polygons: &mut Vec<Polygon>,
+) {
+    match geometry {
+        Geometry::Point(point) => points.push(*point),
+        Geometry::MultiPoint(multi_point) => points.extend(multi_point.iter().copied()),
+        Geometry::Line(line) => lines.push(LineString::from(*line)),
+        Geometry::LineString(line) => lines.push(line.clone()),
+        Geometry::MultiLineString(multi_line) => lines.extend(multi_line.iter().cloned()),
+        Geometry::Polygon(polygon) => polygons.push(polygon.clone()),
+        Geometry::MultiPolygon(multi_polygon) => polygons.extend(multi_polygon.iter().cloned()),
+        Geometry::Rect(rect) => polygons.push(rect.to_polygon()),
+        Geometry::Triangle(triangle) => polygons.push(triangle.to_polygon()),
+        Geometry::GeometryCollection(GeometryCollection(collection)) => {
+            for geometry in collection {
+                split_geometry(geometry, points, lines, polygons);
+            }
+        }
+    }
+}
+
+/// Return the distance in meters between the closest points of the two geometries.
+/// The closest points between two disjoint geometries are always on one of their vertices.
+pub(crate) fn geometries_distance(left: &Geometry, right: &Geometry) -> f64 {
+    if left.intersects(right) {
+        return 0.0;
+    }
+    let left_to_right = left
+        .coords_iter()
+        .map(|coord| distance_to_geometry(&Haversine, right, Point::from(coord)));
+    let right_to_left = right
+        .coords_iter()
+        .map(|coord| distance_to_geometry(&Haversine, left, Point::from(coord)));
+    left_to_right
+        .chain(right_to_left)
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// Return the center of the cell and the distance between its center and its farthest vertex.
+fn cell_center_and_radius<M: Distance<f64, Point, Point>>(
+    measure: &M,
+    cell: CellIndex,
+) -> (Point, f64) {
+    let center = LatLng::from(cell);
+    let center = Point::new(center.lng(), center.lat());
+    let radius = cell
+        .boundary()
+        .iter()
+        .map(|vertex| measure.distance(center, Point::new(vertex.lng(), vertex.lat())))
+        .fold(0.0, f64::max);
+    (center, radius)
+}
+
+/// Return a distance that is guaranteed to be lower or equal to the distance
+/// between the point and any point of the cell.
+pub(crate) fn cell_lower_bound_distance<M: Distance<f64, Point, Point>>(
+    measure: &M,
+    cell: CellIndex,
+    point: Point,
+) -> f64 {
+    let (center, radius) = cell_center_and_radius(measure, cell);
+    (measure.distance(center, point) - radius).max(0.0)
+}
+
+/// Return `true` if all the points of the cell are at less than `radius` of the point.
+pub(crate) fn cell_is_within<M: Distance<f64, Point, Point>>(
+    measure: &M,
+    cell: CellIndex,
+    point: Point,
+    radius: f64,
+) -> bool {
+    // Past a quarter of the earth the circle is not convex anymore and checking the vertices is not enough
+    const QUARTER_OF_THE_EARTH: f64 = 10_000_000.0;
+    if radius < QUARTER_OF_THE_EARTH {
+        cell.boundary()
+            .iter()
+            .all(|vertex| measure.distance(point, Point::new(vertex.lng(), vertex.lat())) <= radius)
+    } else {
+        let (center, cell_radius) = cell_center_and_radius(measure, cell);
+        measure.distance(center, point) + cell_radius <= radius
+    }
+}
+
+/// Return the distance between the point and the closest point of the shape.
+pub(crate) fn distance_to_geometry<M: Distance<f64, Point, Point>>(
+    measure: &M,
+    shape: &Geometry,
+    point: Point,
+) -> f64 {
+    match shape.haversine_closest_point(&point) {
+        Closest::Intersection(_) => 0.0,
+        Closest::SinglePoint(closest) => measure.distance(closest, point),
+        // Happens on degenerated shapes, we fallback on the closest coordinate
+        Closest::Indeterminate => shape
+            .coords_iter()
+            .map(|coord| measure.distance(Point::from(coord), point))
+            .fold(f64::INFINITY, f64::min),
+    }
+}
@@ -2,12 +2,17 @@ use std::ops::Deref;
 
 use geo::{GeometryCollection, point, polygon};
 use geojson::{FeatureCollection, GeoJson};
-use h3o::LatLng;
-use heed::{Env, EnvOpenOptions, RoTxn, WithTls};
+use h3o::{CellIndex, LatLng};
+use heed::{Env, EnvOpenOptions, RoTxn, WithTls, types::DecodeIgnore};
 use steppe::NoProgress;
 use tempfile::TempDir;
 
-use crate::{Cellulite, Key};
+use crate::{
+    Cellulite, Key, Problem, Settings,
+    builder::get_children_cells,
+    keys::MetadataKey,
+    reader::{Filter, Predicate},
+};
 
 pub struct DatabaseHandle {
     pub env: Env<WithTls>,
@@ -96,7 +101,9 @@ fn create_database() -> DatabaseHandle {
 fn basic_write() {
     let mut db = create_database();
     let mut wtxn = db.env.write_txn().unwrap();
-    db.database.threshold = 3;
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
     let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
         0.0, 0.0,
     ])));
@@ -188,7 +195,9 @@ fn bug_write_points_create_cells_too_deep() {
     // This simple test was creating 5 cells instead of 3 with two cells too deep for on reason.
     let mut db = create_database();
     let mut wtxn = db.env.write_txn().unwrap();
-    db.database.threshold = 2;
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
     let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
         -11.460678226504395,
         48.213563161838714,
@@ -218,7 +227,9 @@ fn bug_write_points_create_unrelated_cells() {
     // This simple test was creating 4 cells instead of 3 with two completely unrelated cells.
     let mut db = create_database();
     let mut wtxn = db.env.write_txn().unwrap();
-    db.database.threshold = 2;
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
     let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
         6.0197316417968105,
         49.63676497357687,
@@ -242,6 +253,8 @@ fn bug_write_points_create_unrelated_cells() {
     Cell { res: 1, center: (40.9713, 2.8207) }: RoaringBitmap<[1]>
     # Belly Cells
     ");
+    // An unrelated cell would be reported since the point doesn't intersect it
+    assert_eq!(db.check(&wtxn).unwrap(), vec![]);
 }
 
 #[test]
@@ -266,7 +279,9 @@ fn query_points_on_transmeridian_cell() {
 
     let mut db = create_database();
     let mut wtxn = db.env.write_txn().unwrap();
-    db.database.threshold = 2;
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
 
     db.add(&mut wtxn, 0, &lake).unwrap();
     db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
@@ -315,7 +330,9 @@ fn store_all_kind_of_collection() {
     // Purpose of the test is just to make sure w e can store all kinds of collection
     let mut db = create_database();
     let mut wtxn = db.env.write_txn().unwrap();
-    db.database.threshold = 2;
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
     let geometry_collection = geojson::Value::GeometryCollection(vec![geojson::Geometry::new(
         geojson::Value::Point(vec![6.0197316417968105, 49.63676497357687]),
     )]);
@@ -388,7 +405,10 @@ fn write_polygon_with_belly_cells_at_res0() {
     // normal cells for its edges.
     let mut cellulite = create_database();
     let mut wtxn = cellulite.env.write_txn().unwrap();
-    cellulite.database.threshold = 2;
+    cellulite
+        .database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
     let point = GeometryCollection::from(point! { x:-10.38791, y: 51.68380 });
     cellulite
         .add(&mut wtxn, 0, &FeatureCollection::from(&point).into())
@@ -437,7 +457,10 @@ fn write_polygon_with_belly_cells_at_res1() {
     // same test as above except we're doing everything at the res1 to be sure both the code at resolution 0 and 1 works
     let mut cellulite = create_database();
     let mut wtxn = cellulite.env.write_txn().unwrap();
-    cellulite.database.threshold = 2;
+    cellulite
+        .database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
     let point = GeometryCollection::from(point! { x:-10.89288, y: 52.91525 });
     cellulite
         .add(&mut wtxn, 0, &FeatureCollection::from(&point).into())
@@ -482,44 +505,1425 @@ fn write_polygon_with_belly_cells_at_res1() {
     insta::assert_debug_snapshot!(res, @"RoaringBitmap<[0, 1]>");
 }
 
-/*
 #[test]
 fn basic_nearest() {
-    let handle = create_database();
-    let mut wtxn = handle.env.write_txn().unwrap();
-    let mut writer = Writer::new(handle.database);
-    writer.threshold = 10;
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(10))
+        .unwrap();
     // We'll draw a simple line over the y as seen below
     // (0,0) # # # # # # ...
     for i in 0..100 {
-        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![i as f64, 0.0])));
-        writer.add_item(&mut wtxn, i, &point).unwrap();
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+            i as f64, 0.0,
+        ])));
+        db.add(&mut wtxn, i, &point).unwrap();
     }
-    // insta::assert_snapshot!(handle.snap(&wtxn), @r###"
-    // "###);
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
     wtxn.commit().unwrap();
 
-    let rtxn = handle.env.read_txn().unwrap();
-    let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![0.0, 0.0])));
-    let ret = writer.nearest_point(&rtxn, &point, 5).unwrap();
-
-    insta::assert_snapshot!(NnRes(Some(ret)), @r###"
-        id(0): coord(0.00000, 0.00000)
-        id(1): coord(1.00000, -0.00000)
-        id(2): coord(2.00000, 0.00000)
-        id(3): coord(3.00000, -0.00000)
-        id(4): coord(4.00000, 0.00000)
-        "###);
-
-    let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![50.0, 0.0])));
-    let ret = writer.nearest_point(&rtxn, &point, 5).unwrap();
-
-    insta::assert_snapshot!(NnRes(Some(ret)), @r###"
-        id(50): coord(50.00000, 0.00001)
-        id(49): coord(49.00000, 0.00000)
-        id(51): coord(51.00000, -0.00000)
-        id(48): coord(48.00000, 0.00000)
-        id(52): coord(52.00000, 0.00000)
-        "###);
-}
-*/
+    let rtxn = db.env.read_txn().unwrap();
+    let nearest_in_km = |point: geo::Point, k: usize| {
+        let mut ret: Vec<_> = db
+            .nearest(&rtxn, point, k)
+            .unwrap()
+            .into_iter()
+            .map(|(item, distance)| (item, (distance / 1000.0).round() as u64))
+            .collect();
+        // items at the same distance can be returned in any order
+        ret.sort_by_key(|(item, distance)| (*distance, *item));
+        ret
+    };
+
+    let ret = nearest_in_km(point! { x: 0.0, y: 0.0 }, 5);
+    assert_eq!(ret, vec![(0, 0), (1, 111), (2, 222), (3, 334), (4, 445)]);
+
+    let ret = nearest_in_km(point! { x: 50.0, y: 0.0 }, 5);
+    assert_eq!(
+        ret,
+        vec![(50, 0), (49, 111), (51, 111), (48, 222), (52, 222)]
+    );
+
+    let ret = nearest_in_km(point! { x: 120.0, y: 0.0 }, 2);
+    assert_eq!(ret, vec![(99, 2335), (98, 2446)]);
+
+    let ret = nearest_in_km(point! { x: 0.0, y: 0.0 }, 0);
+    assert_eq!(ret, vec![]);
+}
+
+#[test]
+fn query_by_geometry() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for (id, (x, y)) in [(1.0, 1.0), (10.0, 10.0), (5.0, 5.0)]
+        .into_iter()
+        .enumerate()
+    {
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![x, y])));
+        db.add(&mut wtxn, id as u32, &point).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let first_island =
+        polygon![(x: 0.0, y: 0.0), (x: 2.0, y: 0.0), (x: 2.0, y: 2.0), (x: 0.0, y: 2.0)];
+    let second_island =
+        polygon![(x: 9.0, y: 9.0), (x: 11.0, y: 9.0), (x: 11.0, y: 11.0), (x: 9.0, y: 11.0)];
+    let islands = geo::MultiPolygon::new(vec![first_island, second_island]);
+    let ret = db.in_geometry(&wtxn, &islands.clone().into()).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+
+    let with_hole = polygon!(
+        exterior: [(x: 0.0, y: 0.0), (x: 12.0, y: 0.0), (x: 12.0, y: 12.0), (x: 0.0, y: 12.0)],
+        interiors: [[(x: 4.0, y: 4.0), (x: 6.0, y: 4.0), (x: 6.0, y: 6.0), (x: 4.0, y: 6.0)]],
+    );
+    let ret = db.in_geometry(&wtxn, &with_hole.clone().into()).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+
+    let collection = GeometryCollection(vec![islands.into(), with_hole.into()]);
+    let ret = db.in_geometry(&wtxn, &collection.clone().into()).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+
+    let geojson = GeoJson::from(geojson::Geometry::new(geojson::Value::from(&collection)));
+    let ret = db.in_geojson(&wtxn, &geojson).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+
+    let line = geo::LineString::from(vec![(0.0, 0.0), (12.0, 12.0)]);
+    let ret = db.in_geometry(&wtxn, &line.into());
+    assert!(matches!(
+        ret,
+        Err(crate::Error::UnsupportedQueryGeometry("LineString"))
+    ));
+}
+
+#[test]
+fn query_with_predicates() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    let large =
+        polygon![(x: 0.0, y: 0.0), (x: 10.0, y: 0.0), (x: 10.0, y: 10.0), (x: 0.0, y: 10.0)];
+    let small = polygon![(x: 4.0, y: 4.0), (x: 6.0, y: 4.0), (x: 6.0, y: 6.0), (x: 4.0, y: 6.0)];
+    let items: Vec<geo::Geometry> = vec![
+        large.into(),
+        point! { x: 5.0, y: 5.0 }.into(),
+        small.into(),
+        geo::LineString::from(vec![(1.0, 1.0), (20.0, 20.0)]).into(),
+        point! { x: 50.0, y: 50.0 }.into(),
+    ];
+    for (id, item) in items.iter().enumerate() {
+        let geojson = GeoJson::from(geojson::Geometry::new(geojson::Value::from(item)));
+        db.add(&mut wtxn, id as u32, &geojson).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let query: geo::Geometry =
+        polygon![(x: 3.0, y: 3.0), (x: 7.0, y: 3.0), (x: 7.0, y: 7.0), (x: 3.0, y: 7.0)].into();
+    let ret = db
+        .in_geometry_with_predicate(&wtxn, &query, Predicate::Intersects)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1, 2, 3]>");
+    let ret = db
+        .in_geometry_with_predicate(&wtxn, &query, Predicate::Within)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[1, 2]>");
+    let ret = db
+        .in_geometry_with_predicate(&wtxn, &query, Predicate::Contains)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0]>");
+    let ret = db
+        .in_geometry_with_predicate(&wtxn, &query, Predicate::Covers)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0]>");
+    let ret = db
+        .in_geometry_with_predicate(&wtxn, &query, Predicate::Disjoint)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[4]>");
+}
+
+#[test]
+fn reverse_geocoding() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    let country =
+        polygon![(x: 0.0, y: 40.0), (x: 10.0, y: 40.0), (x: 10.0, y: 50.0), (x: 0.0, y: 50.0)];
+    let region =
+        polygon![(x: 2.0, y: 42.0), (x: 6.0, y: 42.0), (x: 6.0, y: 46.0), (x: 2.0, y: 46.0)];
+    let city = polygon![(x: 3.0, y: 43.0), (x: 4.0, y: 43.0), (x: 4.0, y: 44.0), (x: 3.0, y: 44.0)];
+    let elsewhere =
+        polygon![(x: 20.0, y: 40.0), (x: 30.0, y: 40.0), (x: 30.0, y: 50.0), (x: 20.0, y: 50.0)];
+    for (id, polygon) in [country, region, city, elsewhere].iter().enumerate() {
+        let geojson = GeoJson::from(geojson::Geometry::new(geojson::Value::from(polygon)));
+        db.add(&mut wtxn, id as u32, &geojson).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let ret = db.containing(&wtxn, point! { x: 3.5, y: 43.5 }).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1, 2]>");
+    let ret = db.containing(&wtxn, point! { x: 5.0, y: 45.0 }).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+    let ret = db.containing(&wtxn, point! { x: 9.0, y: 49.0 }).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0]>");
+    let ret = db.containing(&wtxn, point! { x: 15.0, y: 45.0 }).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[]>");
+}
+
+#[test]
+fn query_bbox() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for (id, (x, y)) in [(179.5, 0.0), (-179.5, 0.0), (0.0, 0.0), (10.0, 10.0)]
+        .into_iter()
+        .enumerate()
+    {
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![x, y])));
+        db.add(&mut wtxn, id as u32, &point).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let bbox = geo::Rect::new(
+        geo::coord! { x: -1.0, y: -1.0 },
+        geo::coord! { x: 11.0, y: 11.0 },
+    );
+    let ret = db.in_bbox(&wtxn, bbox).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[2, 3]>");
+
+    let bbox = geo::Rect::new(
+        geo::coord! { x: -1.0, y: -1.0 },
+        geo::coord! { x: 1.0, y: 1.0 },
+    );
+    let ret = db.in_bbox(&wtxn, bbox).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[2]>");
+
+    // crosses the antimeridian
+    let ret = db
+        .in_bbox_from_corners(
+            &wtxn,
+            geo::coord! { x: 179.0, y: -1.0 },
+            geo::coord! { x: -179.0, y: 1.0 },
+        )
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+}
+
+#[test]
+fn query_exact_circle() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
+    // one point every ~11km on the equator
+    for i in 0..20 {
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+            i as f64 * 0.1,
+            0.0,
+        ])));
+        db.add(&mut wtxn, i, &point).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let ret = db
+        .in_circle_exact(&wtxn, geo::Point::new(0.0, 0.0), 50_000.0)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1, 2, 3, 4]>");
+
+    let ret = db
+        .in_circle_exact(&wtxn, geo::Point::new(1.0, 0.0), 15_000.0)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[9, 10, 11]>");
+
+    let ret = db
+        .in_circle_exact(&wtxn, geo::Point::new(1.0, 1.0), 15_000.0)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[]>");
+}
+
+#[test]
+fn query_within_distance() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
+    // one point every ~11km on the equator
+    for i in 0..20 {
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+            i as f64 * 0.1,
+            0.0,
+        ])));
+        db.add(&mut wtxn, i, &point).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    // a road parallel to the equator, ~11km north of it
+    let road = geo::Geometry::LineString(geo::line_string![(x: 0.0, y: 0.1), (x: 1.0, y: 0.1)]);
+    let ret = db.within_distance(&wtxn, &road, 12_000.0).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]>");
+    let ret = db.within_distance(&wtxn, &road, 5_000.0).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[]>");
+
+    let zone = geo::Geometry::Polygon(geo::polygon![
+        (x: 0.45, y: -0.05),
+        (x: 0.55, y: -0.05),
+        (x: 0.55, y: 0.05),
+        (x: 0.45, y: 0.05),
+    ]);
+    let ret = db.within_distance(&wtxn, &zone, 0.0).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[5]>");
+    let ret = db.within_distance(&wtxn, &zone, 6_000.0).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[4, 5, 6]>");
+}
+
+#[test]
+fn delete_items_in_split_cells() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for i in 0..10 {
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+            i as f64 * 0.001,
+            0.0,
+        ])));
+        db.add(&mut wtxn, i, &point).unwrap();
+    }
+    // a large polygon that will end up in the belly of the cells around the points
+    let polygon = GeoJson::from(geojson::Geometry::new(geojson::Value::Polygon(vec![vec![
+        vec![-1.0, -1.0],
+        vec![1.0, -1.0],
+        vec![1.0, 1.0],
+        vec![-1.0, 1.0],
+        vec![-1.0, -1.0],
+    ]])));
+    db.add(&mut wtxn, 10, &polygon).unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert!(db.inner_belly_cells(&wtxn).unwrap().count() > 0);
+
+    let mut deleted = roaring::RoaringBitmap::new();
+    for item in [0, 1, 2, 3, 4, 10] {
+        db.delete(&mut wtxn, item).unwrap();
+        deleted.insert(item);
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    for ret in db.cell.iter(&wtxn).unwrap() {
+        let (_key, bitmap) = ret.unwrap();
+        assert!(!bitmap.is_empty());
+        assert!(bitmap.is_disjoint(&deleted), "{bitmap:?}");
+    }
+    for item in deleted.iter() {
+        assert!(db.item(&wtxn, item).unwrap().is_none());
+    }
+
+    let ret = db
+        .in_shape(
+            &wtxn,
+            &polygon![
+                (x: -0.5, y: -0.5),
+                (x: 0.5, y: -0.5),
+                (x: 0.5, y: 0.5),
+                (x: -0.5, y: 0.5),
+            ],
+        )
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[5, 6, 7, 8, 9]>");
+}
+
+#[test]
+fn compact_after_deletions() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for i in 0..10 {
+        let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+            i as f64 * 0.001,
+            0.0,
+        ])));
+        db.add(&mut wtxn, i, &point).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert!(
+        db.inner_db_cells(&wtxn)
+            .unwrap()
+            .any(|ret| ret.unwrap().0.resolution() != h3o::Resolution::Zero)
+    );
+
+    for item in 1..10 {
+        db.delete(&mut wtxn, item).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    db.compact(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    insta::assert_snapshot!(db.snap(&wtxn), @r"
+    # Version: 0.2.0
+    # Items
+    0: Point(Zoint { lng: 0.0, lat: 0.0 })
+    # Cells
+    Cell { res: 0, center: (2.3009, -5.2454) }: RoaringBitmap<[0]>
+    # Belly Cells
+    ");
+    let ret = db
+        .in_circle_exact(&wtxn, geo::Point::new(0.0, 0.0), 1_000.0)
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0]>");
+}
+
+#[test]
+fn add_invalid_geojson() {
+    let db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+
+    let feature = GeoJson::Feature(geojson::Feature {
+        bbox: None,
+        geometry: None,
+        id: None,
+        properties: None,
+        foreign_members: None,
+    });
+    let ret = db.add(&mut wtxn, 0, &feature);
+    assert!(matches!(ret, Err(crate::Error::InvalidGeoJson(_))));
+
+    let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+        12.0, 91.0,
+    ])));
+    let ret = db.add(&mut wtxn, 1, &point).unwrap_err();
+    insta::assert_snapshot!(ret, @"Item `1` has invalid coordinates (lat: 91, lng: 12). The latitude must be between -90 and 90 and the longitude between -180 and 180.");
+
+    let line = GeoJson::from(geojson::Geometry::new(geojson::Value::LineString(vec![
+        vec![0.0, 0.0],
+        vec![f64::NAN, 0.0],
+    ])));
+    let ret = db.add(&mut wtxn, 2, &line).unwrap_err();
+    insta::assert_snapshot!(ret, @"Item `2` has invalid coordinates (lat: 0, lng: NaN). The latitude must be between -90 and 90 and the longitude between -180 and 180.");
+
+    // Nothing was inserted
+    assert!(db.item(&wtxn, 0).unwrap().is_none());
+    assert!(db.item(&wtxn, 1).unwrap().is_none());
+    assert!(db.item(&wtxn, 2).unwrap().is_none());
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+}
+
+#[test]
+fn add_wkt_and_wkb() {
+    let db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+
+    db.add_geometry(&mut wtxn, 0, &point! { x: 1.0, y: 2.0 }.into())
+        .unwrap();
+    db.add_wkt(&mut wtxn, 1, "POINT(7 8)").unwrap();
+
+    // little endian WKB point
+    let mut wkb = vec![1, 1, 0, 0, 0];
+    wkb.extend(3.0_f64.to_le_bytes());
+    wkb.extend(4.0_f64.to_le_bytes());
+    db.add_wkb(&mut wtxn, 2, &wkb).unwrap();
+
+    // big endian EWKB point with a SRID and a Z coordinate
+    let mut ewkb = vec![0];
+    ewkb.extend((1_u32 | 0x8000_0000 | 0x2000_0000).to_be_bytes());
+    ewkb.extend(4326_u32.to_be_bytes());
+    ewkb.extend(5.0_f64.to_be_bytes());
+    ewkb.extend(6.0_f64.to_be_bytes());
+    ewkb.extend(100.0_f64.to_be_bytes());
+    db.add_wkb(&mut wtxn, 3, &ewkb).unwrap();
+
+    insta::assert_snapshot!(db.snap(&wtxn), @r"
+    # Version: 0.2.0
+    # Items
+    0: Point(Zoint { lng: 1.0, lat: 2.0 })
+    1: Point(Zoint { lng: 7.0, lat: 8.0 })
+    2: Point(Zoint { lng: 3.0, lat: 4.0 })
+    3: Point(Zoint { lng: 5.0, lat: 6.0 })
+    # Cells
+    # Belly Cells
+    ");
+
+    db.add_wkt(&mut wtxn, 4, "LINESTRING(0 0, 1 1)").unwrap();
+    assert!(matches!(
+        db.item(&wtxn, 4).unwrap(),
+        Some(zerometry::Zerometry::Line(_))
+    ));
+
+    let ret = db.add_wkt(&mut wtxn, 5, "LINESTRING(0 0, 1").unwrap_err();
+    assert!(matches!(ret, crate::Error::InvalidWkt(_)));
+    let ret = db.add_wkb(&mut wtxn, 5, &wkb[..10]).unwrap_err();
+    assert!(matches!(ret, crate::Error::InvalidWkb(_)));
+    let ret = db.add_wkt(&mut wtxn, 5, "POINT(0 100)").unwrap_err();
+    assert!(matches!(ret, crate::Error::InvalidCoordinates(5, _, _)));
+}
+
+#[test]
+fn add_malformed_wkb() {
+    let db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+
+    // A collection containing a collection containing a collection... down to a point
+    let nested = |depth: usize| {
+        let mut wkb = Vec::new();
+        for _ in 0..depth {
+            wkb.extend([1, 7, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        wkb.extend([1, 1, 0, 0, 0]);
+        wkb.extend(3.0_f64.to_le_bytes());
+        wkb.extend(4.0_f64.to_le_bytes());
+        wkb
+    };
+    db.add_wkb(&mut wtxn, 0, &nested(10)).unwrap();
+
+    // Every truncation of a valid geometry must be rejected
+    let wkb = nested(10);
+    for len in 0..wkb.len() {
+        db.add_wkb(&mut wtxn, 1, &wkb[..len]).unwrap_err();
+    }
+
+    // Trying to insert an empty point
+    let mut wkb = vec![1, 1, 0, 0, 0];
+    wkb.extend(f64::NAN.to_le_bytes());
+    wkb.extend(f64::NAN.to_le_bytes());
+    let ret = db.add_wkb(&mut wtxn, 1, &wkb).unwrap_err();
+    insta::assert_snapshot!(ret, @"Invalid WKB: empty points are not supported");
+}
+
+#[test]
+fn add_raw_zerometry() {
+    let db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+
+    let mut bytes = Vec::new();
+    zerometry::Zerometry::write_from_geometry(&mut bytes, &point! { x: 1.0, y: 2.0 }.into())
+        .unwrap();
+    db.add_raw_zerometry(&mut wtxn, 0, &bytes).unwrap();
+    // the buffer doesn't need to be aligned
+    let mut unaligned = vec![0];
+    unaligned.extend_from_slice(&bytes);
+    db.add_raw_zerometry(&mut wtxn, 1, &unaligned[1..]).unwrap();
+
+    insta::assert_snapshot!(db.snap(&wtxn), @r"
+    # Version: 0.2.0
+    # Items
+    0: Point(Zoint { lng: 1.0, lat: 2.0 })
+    1: Point(Zoint { lng: 1.0, lat: 2.0 })
+    # Cells
+    # Belly Cells
+    ");
+
+    let ret = db.add_raw_zerometry(&mut wtxn, 2, &bytes[..bytes.len() - 1]);
+    assert!(matches!(ret, Err(crate::Error::InvalidZerometry(2, _))));
+    let mut trailing = bytes.clone();
+    trailing.extend_from_slice(&[0; 8]);
+    let ret = db.add_raw_zerometry(&mut wtxn, 2, &trailing);
+    assert!(matches!(ret, Err(crate::Error::InvalidZerometry(2, _))));
+
+    // The offsets of the multi shapes and collections must stay in the buffer
+    let multi_polygon = geo::MultiPolygon::new(vec![
+        polygon![(x: 0., y: 0.), (x: 1., y: 0.), (x: 1., y: 1.), (x: 0., y: 0.)],
+        polygon![(x: 2., y: 2.), (x: 3., y: 2.), (x: 3., y: 3.), (x: 2., y: 2.)],
+    ]);
+    let collection = GeometryCollection(vec![
+        point! { x: 1.0, y: 2.0 }.into(),
+        multi_polygon.clone().into(),
+    ]);
+    for geometry in [geo::Geometry::from(multi_polygon), collection.into()] {
+        let mut bytes = Vec::new();
+        zerometry::Zerometry::write_from_geometry(&mut bytes, &geometry).unwrap();
+        db.add_raw_zerometry(&mut wtxn, 2, &bytes).unwrap();
+        // corrupt every u32 of the header, after the tag and the bounding box
+        for offset in (40..bytes.len().min(72)).step_by(4) {
+            let mut corrupted = bytes.clone();
+            corrupted[offset..offset + 4].copy_from_slice(&u32::MAX.to_ne_bytes());
+            let ret = db.add_raw_zerometry(&mut wtxn, 3, &corrupted);
+            assert!(matches!(ret, Err(crate::Error::InvalidZerometry(3, _))));
+        }
+        for len in 0..bytes.len() {
+            let ret = db.add_raw_zerometry(&mut wtxn, 3, &bytes[..len]);
+            assert!(matches!(ret, Err(crate::Error::InvalidZerometry(3, _))));
+        }
+    }
+    db.delete(&mut wtxn, 2).unwrap();
+
+    let mut bytes = Vec::new();
+    zerometry::Zerometry::write_from_geometry(&mut bytes, &point! { x: 200.0, y: 2.0 }.into())
+        .unwrap();
+    let ret = db.add_raw_zerometry(&mut wtxn, 2, &bytes);
+    assert!(matches!(
+        ret,
+        Err(crate::Error::InvalidCoordinates(2, _, _))
+    ));
+    assert!(db.item(&wtxn, 2).unwrap().is_none());
+}
+
+#[test]
+fn bulk_load() {
+    let mut items: Vec<(u32, geo::Geometry)> = (0..50)
+        .map(|i| {
+            let point = point! { x: (i % 10) as f64 * 0.01, y: (i / 10) as f64 * 0.01 };
+            (i, point.into())
+        })
+        .collect();
+    items.push((
+        50,
+        polygon![
+            (x: -1.0, y: -1.0),
+            (x: 1.0, y: -1.0),
+            (x: 1.0, y: 1.0),
+            (x: -1.0, y: 1.0),
+        ]
+        .into(),
+    ));
+    // the last version of an item is kept
+    items.push((3, point! { x: 0.5, y: 0.5 }.into()));
+
+    let mut incremental = create_database();
+    let mut wtxn = incremental.env.write_txn().unwrap();
+    incremental
+        .database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
+    for (id, geometry) in items.iter() {
+        incremental.add_geometry(&mut wtxn, *id, geometry).unwrap();
+    }
+    incremental
+        .build(&mut wtxn, &|| false, &NoProgress)
+        .unwrap();
+    let incremental_snap = incremental.snap(&wtxn);
+    wtxn.commit().unwrap();
+
+    let mut bulk = create_database();
+    let mut wtxn = bulk.env.write_txn().unwrap();
+    bulk.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
+    bulk.bulk_load(&mut wtxn, items.clone(), &|| false, &NoProgress)
+        .unwrap();
+    assert_eq!(bulk.snap(&wtxn), incremental_snap);
+
+    let ret = bulk.bulk_load(&mut wtxn, items, &|| false, &NoProgress);
+    assert!(matches!(ret, Err(crate::Error::BulkLoadOnNonEmptyDatabase)));
+}
+
+#[test]
+fn item_properties() {
+    let db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+
+    let feature: GeoJson = r#"{
+        "type": "Feature",
+        "geometry": { "type": "Point", "coordinates": [1.0, 2.0] },
+        "properties": { "name": "tamo", "age": 28 }
+    }"#
+    .parse()
+    .unwrap();
+    db.add(&mut wtxn, 0, &feature).unwrap();
+    db.add_geometry(&mut wtxn, 1, &point! { x: 1.5, y: 2.5 }.into())
+        .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let properties = db.properties(&wtxn, 0).unwrap().unwrap();
+    insta::assert_snapshot!(geojson::JsonValue::Object(properties), @r#"{"age":28,"name":"tamo"}"#);
+    assert!(db.properties(&wtxn, 1).unwrap().is_none());
+
+    let ret = db
+        .in_shape(
+            &wtxn,
+            &polygon![
+                (x: 0.0, y: 0.0),
+                (x: 2.0, y: 0.0),
+                (x: 2.0, y: 3.0),
+                (x: 0.0, y: 3.0),
+            ],
+        )
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1]>");
+    let features = db.features(&wtxn, &ret).unwrap();
+    assert_eq!(features.features.len(), 2);
+    let feature = &features.features[0];
+    assert_eq!(feature.id, Some(geojson::feature::Id::Number(0.into())));
+    assert_eq!(
+        feature.geometry,
+        Some(geojson::Geometry::new(geojson::Value::Point(vec![
+            1.0, 2.0
+        ])))
+    );
+    assert_eq!(feature.property("name").unwrap(), "tamo");
+    let feature = &features.features[1];
+    assert_eq!(feature.id, Some(geojson::feature::Id::Number(1.into())));
+    assert!(feature.properties.is_none());
+
+    // Adding a geometry without properties removes the previous properties
+    let point = GeoJson::from(geojson::Geometry::new(geojson::Value::Point(vec![
+        1.0, 2.0,
+    ])));
+    db.add(&mut wtxn, 0, &point).unwrap();
+    assert!(db.properties(&wtxn, 0).unwrap().is_none());
+    // And so do all the other ways to add an item
+    db.add(&mut wtxn, 0, &feature).unwrap();
+    db.add_wkt(&mut wtxn, 0, "POINT(1 2)").unwrap();
+    assert!(db.properties(&wtxn, 0).unwrap().is_none());
+
+    // Deleting an item removes its properties with the next build
+    let mut properties = geojson::JsonObject::new();
+    properties.insert(String::from("name"), "kefir".into());
+    db.set_properties(&mut wtxn, 1, &properties).unwrap();
+    assert_eq!(db.properties(&wtxn, 1).unwrap(), Some(properties));
+    db.delete(&mut wtxn, 1).unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert!(db.properties(&wtxn, 1).unwrap().is_none());
+    assert!(db.feature(&wtxn, 1).unwrap().is_none());
+}
+
+#[test]
+fn properties_database_is_optional() {
+    let feature: GeoJson = r#"{
+        "type": "Feature",
+        "geometry": { "type": "Point", "coordinates": [1.0, 2.0] },
+        "properties": { "name": "tamo" }
+    }"#
+    .parse()
+    .unwrap();
+
+    // An environment opened with the number of databases of v0.2.0 cannot hold a new database
+    let dir = tempfile::tempdir().unwrap();
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .map_size(200 * 1024 * 1024)
+            .max_dbs(4)
+            .open(dir.path())
+    }
+    .unwrap();
+    let mut wtxn = env.write_txn().unwrap();
+    let ret = Cellulite::create_from_env(&env, &mut wtxn, "cellulite");
+    assert!(matches!(
+        ret,
+        Err(crate::Error::Heed(heed::Error::Mdb(
+            heed::MdbError::DbsFull
+        )))
+    ));
+    drop(wtxn);
+
+    // A database written by v0.2.0 can be opened and upgraded
+    let dir = tempfile::tempdir().unwrap();
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .map_size(200 * 1024 * 1024)
+            .max_dbs(Cellulite::nb_dbs())
+            .open(dir.path())
+    }
+    .unwrap();
+    let mut wtxn = env.write_txn().unwrap();
+    for name in ["item", "cell", "update", "metadata"] {
+        env.create_database::<DecodeIgnore, DecodeIgnore>(
+            &mut wtxn,
+            Some(&format!("cellulite-{name}")),
+        )
+        .unwrap();
+    }
+    let mut cellulite = Cellulite::open_from_env(&env, &wtxn, "cellulite").unwrap();
+    assert!(cellulite.properties_db_stats(&wtxn).unwrap().is_none());
+    assert!(cellulite.properties(&wtxn, 0).unwrap().is_none());
+    let ret = cellulite.add(&mut wtxn, 0, &feature).unwrap_err();
+    insta::assert_snapshot!(ret, @"The properties database doesn't exist. Open the environment with `Cellulite::nb_dbs()` databases and call `upgrade` to create it.");
+    assert!(cellulite.item(&wtxn, 0).unwrap().is_none());
+    cellulite
+        .upgrade(
+            &env,
+            &mut wtxn,
+            "cellulite",
+            Cellulite::default_threshold(),
+            &NoProgress,
+        )
+        .unwrap();
+    cellulite.add(&mut wtxn, 0, &feature).unwrap();
+    wtxn.commit().unwrap();
+
+    let rtxn = env.read_txn().unwrap();
+    let cellulite = Cellulite::open_from_env(&env, &rtxn, "cellulite").unwrap();
+    let properties = cellulite.properties(&rtxn, 0).unwrap().unwrap();
+    insta::assert_snapshot!(geojson::JsonValue::Object(properties), @r#"{"name":"tamo"}"#);
+}
+
+#[test]
+fn query_with_filter() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for i in 0..10 {
+        db.add_geometry(&mut wtxn, i, &point! { x: i as f64 * 0.1, y: 0.0 }.into())
+            .unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let shape = polygon![
+        (x: -0.05, y: -0.05),
+        (x: 0.55, y: -0.05),
+        (x: 0.55, y: 0.05),
+        (x: -0.05, y: 0.05),
+    ];
+    let ret = db.in_shape(&wtxn, &shape).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1, 2, 3, 4, 5]>");
+
+    let allowlist = roaring::RoaringBitmap::from_iter([1, 3, 7]);
+    let ret = db
+        .in_shape_with_filter(&wtxn, &shape, &Filter::Allowlist(&allowlist))
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[1, 3]>");
+
+    let ret = db
+        .in_shape_with_filter(&wtxn, &shape, &Filter::Closure(&|item| item % 2 == 0))
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 2, 4]>");
+
+    let ret = db
+        .in_geometry_with_filter(
+            &wtxn,
+            &shape.into(),
+            &Filter::Allowlist(&roaring::RoaringBitmap::new()),
+        )
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[]>");
+}
+
+#[test]
+fn query_iter_and_limit() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for i in 0..10 {
+        db.add_geometry(&mut wtxn, i, &point! { x: i as f64 * 0.1, y: 0.0 }.into())
+            .unwrap();
+    }
+    // a large polygon that will end up in the belly of the cells around the points
+    db.add_geometry(
+        &mut wtxn,
+        10,
+        &polygon![
+            (x: -1.0, y: -1.0),
+            (x: 2.0, y: -1.0),
+            (x: 2.0, y: 1.0),
+            (x: -1.0, y: 1.0),
+        ]
+        .into(),
+    )
+    .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let shape = polygon![
+        (x: -0.05, y: -0.05),
+        (x: 0.55, y: -0.05),
+        (x: 0.55, y: 0.05),
+        (x: -0.05, y: 0.05),
+    ];
+    let expected = db.in_shape(&wtxn, &shape).unwrap();
+    insta::assert_debug_snapshot!(expected, @"RoaringBitmap<[0, 1, 2, 3, 4, 5, 10]>");
+
+    let items = db
+        .in_shape_iter(&wtxn, &shape)
+        .unwrap()
+        .collect::<crate::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(items.len() as u64, expected.len());
+    assert_eq!(
+        items.iter().copied().collect::<roaring::RoaringBitmap>(),
+        expected
+    );
+
+    let ret = db.in_shape_with_limit(&wtxn, &shape, 3).unwrap();
+    assert_eq!(ret.len(), 3);
+    assert!(ret.is_subset(&expected));
+
+    let ret = db.in_shape_with_limit(&wtxn, &shape, 100).unwrap();
+    assert_eq!(ret, expected);
+
+    // The filters are applied to the lazy iterator as well
+    let allowlist = roaring::RoaringBitmap::from_iter([1, 3, 7, 10]);
+    let ret = db
+        .in_shape_iter_with_filter(&wtxn, &shape, &Filter::Allowlist(&allowlist))
+        .unwrap()
+        .collect::<crate::Result<roaring::RoaringBitmap>>()
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[1, 3, 10]>");
+    let even = |item: u32| item % 2 == 0;
+    let ret = db
+        .in_shape_iter_with_filter(&wtxn, &shape, &Filter::Closure(&even))
+        .unwrap()
+        .collect::<crate::Result<roaring::RoaringBitmap>>()
+        .unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 2, 4, 10]>");
+}
+
+#[test]
+fn count_in_shape() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for i in 0..10 {
+        db.add_geometry(&mut wtxn, i, &point! { x: i as f64 * 0.1, y: 0.0 }.into())
+            .unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let shape = polygon![
+        (x: -0.05, y: -0.05),
+        (x: 0.55, y: -0.05),
+        (x: 0.55, y: 0.05),
+        (x: -0.05, y: 0.05),
+    ];
+    let count = db.in_shape(&wtxn, &shape).unwrap().len();
+    insta::assert_snapshot!(count, @"6");
+
+    let estimate = db.estimate_count_in_shape(&wtxn, &shape).unwrap();
+    assert!(estimate.lower_bound <= count);
+    assert!(count <= estimate.upper_bound);
+    assert!(estimate.estimate().abs_diff(count) <= estimate.error());
+
+    // Nothing is certain in a shape smaller than the cells
+    let shape = polygon![
+        (x: 0.09, y: -0.01),
+        (x: 0.11, y: -0.01),
+        (x: 0.11, y: 0.01),
+        (x: 0.09, y: 0.01),
+    ];
+    let estimate = db.estimate_count_in_shape(&wtxn, &shape).unwrap();
+    assert_eq!(estimate.lower_bound, 0);
+    assert!(estimate.upper_bound >= 1);
+    assert_eq!(db.in_shape(&wtxn, &shape).unwrap().len(), 1);
+}
+
+#[test]
+fn density() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
+    let mut expected = std::collections::BTreeMap::new();
+    for i in 0..20 {
+        let (lng, lat) = (i as f64 * 0.05, (i % 4) as f64 * 0.05);
+        db.add_geometry(&mut wtxn, i, &point! { x: lng, y: lat }.into())
+            .unwrap();
+        let cell = LatLng::new(lat, lng)
+            .unwrap()
+            .to_cell(h3o::Resolution::Five);
+        *expected.entry(cell).or_insert(0) += 1;
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let area = polygon![
+        (x: -1.0, y: -1.0),
+        (x: 2.0, y: -1.0),
+        (x: 2.0, y: 1.0),
+        (x: -1.0, y: 1.0),
+    ];
+    // Both the cells stored in the database and the cells that were never split are counted right
+    let density = db.density(&wtxn, &area, h3o::Resolution::Five).unwrap();
+    assert_eq!(density, expected);
+
+    let density = db.density(&wtxn, &area, h3o::Resolution::Zero).unwrap();
+    insta::assert_debug_snapshot!(density.values().sum::<u64>(), @"20");
+
+    // The children of a cell are not contained in it, a deep cell can be stored under the neighbour of its parent
+    let mut expected = std::collections::BTreeMap::new();
+    for i in 20..400 {
+        let (lng, lat) = ((i * 37 % 500) as f64 * 0.001, (i * 71 % 300) as f64 * 0.001);
+        db.add_geometry(&mut wtxn, i, &point! { x: lng, y: lat }.into())
+            .unwrap();
+        let cell = LatLng::new(lat, lng)
+            .unwrap()
+            .to_cell(h3o::Resolution::Eight);
+        *expected.entry(cell).or_insert(0) += 1;
+    }
+    for i in 0..20 {
+        db.delete(&mut wtxn, i).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    let area = polygon![
+        (x: -0.01, y: -0.01),
+        (x: 0.51, y: -0.01),
+        (x: 0.51, y: 0.31),
+        (x: -0.01, y: 0.31),
+    ];
+    let density = db.density(&wtxn, &area, h3o::Resolution::Eight).unwrap();
+    assert_eq!(density, expected);
+}
+
+#[test]
+fn upgrade() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.add_geometry(&mut wtxn, 0, &point! { x: 0.0, y: 0.0 }.into())
+        .unwrap();
+
+    // Nothing to do on a database at the current format, the threshold is already stored
+    db.database
+        .upgrade(&db.env, &mut wtxn, "cellulite", 7, &NoProgress)
+        .unwrap();
+    assert_eq!(db.get_format_version(&wtxn).unwrap(), crate::FORMAT_VERSION);
+    assert_eq!(db.settings(), Settings::default());
+
+    // A release that doesn't touch the format can build a database written by an older release
+    let old = crate::metadata::Version {
+        major: 0,
+        minor: 1,
+        patch: 0,
+    };
+    db.set_version(&mut wtxn, &old).unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert_eq!(
+        db.get_version(&wtxn).unwrap(),
+        crate::metadata::Version::default()
+    );
+    let ret = db.in_shape(
+        &wtxn,
+        &polygon![(x: -1.0, y: -1.0), (x: 1.0, y: -1.0), (x: 1.0, y: 1.0), (x: -1.0, y: 1.0)],
+    );
+    insta::assert_debug_snapshot!(ret.unwrap(), @"RoaringBitmap<[0]>");
+
+    // But it can neither build nor upgrade a database written in a more recent format
+    db.set_format_version(&mut wtxn, crate::FORMAT_VERSION + 1)
+        .unwrap();
+    let ret = db.build(&mut wtxn, &|| false, &NoProgress).unwrap_err();
+    insta::assert_snapshot!(ret, @"Format version mismatch while building, was expecting v2 but instead got v3. Upgrade the database before building.");
+    let ret = db
+        .database
+        .upgrade(
+            &db.env,
+            &mut wtxn,
+            "cellulite",
+            Cellulite::default_threshold(),
+            &NoProgress,
+        )
+        .unwrap_err();
+    insta::assert_snapshot!(ret, @"Cannot upgrade the database from the format v3 because it's more recent than the current format v2.");
+}
+
+#[test]
+fn upgrade_from_format_v1() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(3))
+        .unwrap();
+
+    // A leaf cell next to a split cell, the children of the split cell overlap the children of the leaf.
+    // The leaf is the center child of its ancestors so all the items share the same ancestors.
+    let leaf = CellIndex::base_cells()
+        .find(|cell| !cell.is_pentagon())
+        .unwrap()
+        .center_child(h3o::Resolution::Five)
+        .unwrap();
+    let leaf_children = get_children_cells(leaf).unwrap().unwrap();
+    let (neighbour, shared) = leaf
+        .grid_disk::<Vec<_>>(1)
+        .into_iter()
+        .filter(|cell| *cell != leaf)
+        .find_map(|neighbour| {
+            let shared = get_children_cells(neighbour)
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .find(|child| {
+                    leaf_children.contains(child)
+                        && LatLng::from(*child).to_cell(h3o::Resolution::Five) == neighbour
+                })?;
+            Some((neighbour, shared))
+        })
+        .unwrap();
+    // Three items in the shared child so the neighbour is split, and two items in the leaf
+    let mut points: Vec<LatLng> = shared
+        .children(h3o::Resolution::Seven)
+        .map(LatLng::from)
+        .filter(|point| {
+            point.to_cell(h3o::Resolution::Six) == shared
+                && point.to_cell(h3o::Resolution::Five) == neighbour
+        })
+        .take(3)
+        .collect();
+    assert_eq!(points.len(), 3);
+    points.extend([LatLng::from(leaf), LatLng::from(leaf)]);
+    for (i, point) in points.iter().enumerate() {
+        for resolution in h3o::Resolution::range(h3o::Resolution::Zero, h3o::Resolution::Four) {
+            assert_eq!(point.to_cell(resolution), leaf.parent(resolution).unwrap());
+        }
+        let point = point! { x: point.lng(), y: point.lat() };
+        db.add_geometry(&mut wtxn, i as u32, &point.into()).unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    let leaf_items = db.cell.get(&wtxn, &Key::Cell(leaf)).unwrap().unwrap();
+    insta::assert_debug_snapshot!(leaf_items, @"RoaringBitmap<[3, 4]>");
+    let shared_items = db.cell.get(&wtxn, &Key::Cell(shared)).unwrap().unwrap();
+    insta::assert_debug_snapshot!(shared_items, @"RoaringBitmap<[0, 1, 2]>");
+
+    // The databases written before the format v2 only stored their version
+    for key in [
+        MetadataKey::FormatVersion,
+        MetadataKey::Threshold,
+        MetadataKey::MinResolution,
+        MetadataKey::MaxResolution,
+    ] {
+        db.metadata.delete(&mut wtxn, &key).unwrap();
+    }
+    db.database = Cellulite::open_from_env(&db.env, &wtxn, "cellulite").unwrap();
+    assert_eq!(db.get_format_version(&wtxn).unwrap(), 1);
+    assert_eq!(db.settings(), Settings::default());
+    let ret = db.build(&mut wtxn, &|| false, &NoProgress).unwrap_err();
+    insta::assert_snapshot!(ret, @"Format version mismatch while building, was expecting v2 but instead got v1. Upgrade the database before building.");
+    // Storing the settings must not skip the migration
+    db.database
+        .set_settings(&mut wtxn, Settings::default())
+        .unwrap();
+    assert_eq!(db.get_format_version(&wtxn).unwrap(), 1);
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap_err();
+
+    // The threshold the database was built with is provided instead of taking the default one
+    db.database
+        .upgrade(&db.env, &mut wtxn, "cellulite", 3, &NoProgress)
+        .unwrap();
+    assert_eq!(db.get_format_version(&wtxn).unwrap(), crate::FORMAT_VERSION);
+    assert_eq!(db.settings(), Settings::default().with_threshold(3));
+    let reopened = Cellulite::open_from_env(&db.env, &wtxn, "cellulite").unwrap();
+    assert_eq!(reopened.settings(), db.settings());
+    assert_eq!(db.check(&wtxn).unwrap(), Vec::new());
+
+    // The leaf must not be mistaken for a split cell or its items would be lost
+    let center = LatLng::from(leaf);
+    let (x, y) = (center.lng(), center.lat());
+    let ret = db.in_shape(
+        &wtxn,
+        &polygon![(x: x - 1.0, y: y - 1.0), (x: x + 1.0, y: y - 1.0), (x: x + 1.0, y: y + 1.0), (x: x - 1.0, y: y + 1.0)],
+    );
+    insta::assert_debug_snapshot!(ret.unwrap(), @"RoaringBitmap<[0, 1, 2, 3, 4]>");
+}
+
+#[test]
+fn settings_are_persisted() {
+    let dir = tempfile::tempdir().unwrap();
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .map_size(200 * 1024 * 1024)
+            .max_dbs(Cellulite::nb_dbs())
+            .open(dir.path())
+    }
+    .unwrap();
+    let mut wtxn = env.write_txn().unwrap();
+    let mut cellulite = Cellulite::create_from_env(&env, &mut wtxn, "cellulite").unwrap();
+    assert_eq!(cellulite.settings(), Settings::default());
+
+    let ret = cellulite
+        .set_settings(&mut wtxn, Settings::default().with_threshold(0))
+        .unwrap_err();
+    insta::assert_snapshot!(ret, @"Invalid settings: the threshold must be at least 1.");
+
+    cellulite
+        .set_settings(&mut wtxn, Settings::default().with_threshold(10))
+        .unwrap();
+    cellulite
+        .add_geometry(&mut wtxn, 0, &point! { x: 0.0, y: 0.0 }.into())
+        .unwrap();
+    cellulite.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    wtxn.commit().unwrap();
+
+    // Another process opening the database uses the same settings
+    let rtxn = env.read_txn().unwrap();
+    let reopened = Cellulite::open_from_env(&env, &rtxn, "cellulite").unwrap();
+    assert_eq!(reopened.settings(), Settings::default().with_threshold(10));
+    drop(rtxn);
+
+    // And cannot change them once the database has been built
+    let mut wtxn = env.write_txn().unwrap();
+    let mut reopened = Cellulite::create_from_env(&env, &mut wtxn, "cellulite").unwrap();
+    assert_eq!(reopened.settings(), Settings::default().with_threshold(10));
+    let ret = reopened
+        .set_settings(&mut wtxn, Settings::default().with_threshold(20))
+        .unwrap_err();
+    insta::assert_snapshot!(ret, @"The settings can only be changed before building the database.");
+}
+
+#[test]
+fn resolution_bounds() {
+    let shape = polygon![
+        (x: -0.05, y: -0.05),
+        (x: 0.25, y: -0.05),
+        (x: 0.25, y: 0.05),
+        (x: -0.05, y: 0.05),
+    ];
+
+    // The cells are never split past the maximum resolution
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(
+            &mut wtxn,
+            Settings::default()
+                .with_threshold(1)
+                .with_max_resolution(h3o::Resolution::Three),
+        )
+        .unwrap();
+    for i in 0..5 {
+        db.add_geometry(&mut wtxn, i, &point! { x: i as f64 * 0.001, y: 0.0 }.into())
+            .unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    let stats = db.stats(&wtxn).unwrap();
+    insta::assert_debug_snapshot!(stats.cells_by_resolution.keys().max(), @"Some(Three)");
+    insta::assert_debug_snapshot!(db.in_shape(&wtxn, &shape).unwrap(), @"RoaringBitmap<[0, 1, 2, 3, 4]>");
+    drop(wtxn);
+
+    // And always split until the minimum resolution
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(
+            &mut wtxn,
+            Settings::default().with_min_resolution(h3o::Resolution::Two),
+        )
+        .unwrap();
+    db.add_geometry(&mut wtxn, 0, &point! { x: 0.0, y: 0.0 }.into())
+        .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    let stats = db.stats(&wtxn).unwrap();
+    insta::assert_debug_snapshot!(stats.cells_by_resolution.keys().max(), @"Some(Two)");
+    insta::assert_debug_snapshot!(db.in_shape(&wtxn, &shape).unwrap(), @"RoaringBitmap<[0]>");
+
+    let ret = db
+        .database
+        .set_settings(
+            &mut wtxn,
+            Settings::default()
+                .with_min_resolution(h3o::Resolution::Five)
+                .with_max_resolution(h3o::Resolution::Four),
+        )
+        .unwrap_err();
+    insta::assert_snapshot!(ret, @"Invalid settings: the minimum resolution cannot be greater than the maximum resolution.");
+}
+
+#[test]
+fn check_database() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.add_geometry(&mut wtxn, 0, &point! { x: 0.0, y: 0.0 }.into())
+        .unwrap();
+    db.add_geometry(&mut wtxn, 1, &point! { x: 0.1, y: 0.0 }.into())
+        .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert_eq!(db.check(&wtxn).unwrap(), vec![]);
+
+    // Corrupt the database in every possible way
+    let cell = LatLng::new(0.0, 0.0)
+        .unwrap()
+        .to_cell(h3o::Resolution::Zero);
+    db.add_geometry(&mut wtxn, 2, &point! { x: 0.2, y: 0.0 }.into())
+        .unwrap();
+    db.cell_db()
+        .put(
+            &mut wtxn,
+            &Key::Cell(cell),
+            &roaring::RoaringBitmap::from_iter([0, 42]),
+        )
+        .unwrap();
+    db.cell_db()
+        .put(
+            &mut wtxn,
+            &Key::Belly(cell),
+            &roaring::RoaringBitmap::from_iter([0]),
+        )
+        .unwrap();
+    assert_eq!(
+        db.check(&wtxn).unwrap(),
+        vec![
+            Problem::PendingUpdates(1),
+            Problem::ItemMissingFromLevelZero {
+                item: 1,
+                cell,
+                belly: false
+            },
+            Problem::UnknownItem {
+                item: 42,
+                cell,
+                belly: false
+            },
+            Problem::BellyNotContained { item: 0, cell },
+        ]
+    );
+    drop(wtxn);
+
+    // The cells left after deleting items can be removed with compact
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    for i in 0..3 {
+        db.add_geometry(&mut wtxn, i, &point! { x: i as f64 * 0.001, y: 0.0 }.into())
+            .unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    db.delete(&mut wtxn, 0).unwrap();
+    db.delete(&mut wtxn, 1).unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    let problems = db.check(&wtxn).unwrap();
+    assert!(!problems.is_empty());
+    assert!(
+        problems
+            .iter()
+            .all(|problem| matches!(problem, Problem::UnreachableCell { .. })),
+        "{problems:?}"
+    );
+    db.compact(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert_eq!(db.check(&wtxn).unwrap(), vec![]);
+
+    // The deeper cells are checked as well
+    for i in 10..15 {
+        db.add_geometry(
+            &mut wtxn,
+            i,
+            &point! { x: 10.0 + i as f64 * 0.001, y: 10.0 }.into(),
+        )
+        .unwrap();
+    }
+    db.add_geometry(&mut wtxn, 20, &point! { x: 50.0, y: 50.0 }.into())
+        .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert_eq!(db.check(&wtxn).unwrap(), vec![]);
+    let (cell, bitmap) = db
+        .inner_db_cells(&wtxn)
+        .unwrap()
+        .map(|ret| ret.unwrap())
+        .max_by_key(|(cell, _)| cell.resolution())
+        .unwrap();
+    assert!(cell.resolution() > h3o::Resolution::One);
+
+    // An item that went to the belly of the cell while it doesn't strictly contain it
+    let cell_polygon = geo::MultiPolygon::from(cell);
+    db.add_geometry(&mut wtxn, 21, &cell_polygon.into())
+        .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+    assert_eq!(db.check(&wtxn).unwrap(), vec![]);
+    db.cell_db()
+        .put(
+            &mut wtxn,
+            &Key::Belly(cell),
+            &roaring::RoaringBitmap::from_iter([21]),
+        )
+        .unwrap();
+    // An item that was never dispatched to the cell by its parents and doesn't intersect it
+    db.cell_db()
+        .put(
+            &mut wtxn,
+            &Key::Cell(cell),
+            &(bitmap | roaring::RoaringBitmap::from_iter([20])),
+        )
+        .unwrap();
+    let problems = db.check(&wtxn).unwrap();
+    assert!(problems.contains(&Problem::BellyNotContained { item: 21, cell }));
+    assert!(problems.contains(&Problem::CellNotIntersected { item: 20, cell }));
+    assert!(problems.contains(&Problem::ItemMissingFromParent {
+        item: 20,
+        cell,
+        belly: false
+    }));
+}
+
+#[test]
+fn query_wide_bbox() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    // The southern edge of the bbox follows the 40th parallel while the geodesic between its corners goes way up north
+    for i in 0..5 {
+        db.add_geometry(
+            &mut wtxn,
+            i,
+            &point! { x: i as f64 * 0.01, y: 40.05 }.into(),
+        )
+        .unwrap();
+    }
+    db.add_geometry(&mut wtxn, 5, &point! { x: 0.0, y: 39.95 }.into())
+        .unwrap();
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    let bbox = geo::Rect::new(
+        geo::coord! { x: -60.0, y: 40.0 },
+        geo::coord! { x: 60.0, y: 60.0 },
+    );
+    let ret = db.in_bbox(&wtxn, bbox).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[0, 1, 2, 3, 4]>");
+}
+
+#[test]
+fn overwrite_then_delete_indexed_item() {
+    let mut db = create_database();
+    let mut wtxn = db.env.write_txn().unwrap();
+    db.database
+        .set_settings(&mut wtxn, Settings::default().with_threshold(2))
+        .unwrap();
+    let old_area = polygon![
+        (x: -0.5, y: -0.5),
+        (x: 0.5, y: -0.5),
+        (x: 0.5, y: 0.5),
+        (x: -0.5, y: 0.5),
+    ];
+    for i in 0..5 {
+        db.add_geometry(&mut wtxn, i, &point! { x: i as f64 * 0.01, y: 0.0 }.into())
+            .unwrap();
+    }
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    // Move the items far away, then delete one of them before building
+    for i in 0..5 {
+        db.add_geometry(
+            &mut wtxn,
+            i,
+            &point! { x: 100.0, y: 40.0 + i as f64 * 0.01 }.into(),
+        )
+        .unwrap();
+    }
+    // Overwriting an item twice must keep the cells of its indexed shape
+    db.add_geometry(&mut wtxn, 1, &point! { x: 100.0, y: 40.01 }.into())
+        .unwrap();
+    db.delete(&mut wtxn, 0).unwrap();
+    // The cells are only updated by the build
+    let in_cells = db
+        .cell
+        .iter(&wtxn)
+        .unwrap()
+        .filter(|ret| ret.as_ref().unwrap().1.contains(0))
+        .count();
+    assert_ne!(in_cells, 0);
+    db.build(&mut wtxn, &|| false, &NoProgress).unwrap();
+
+    // None of the items are left in their old cells
+    for ret in db.cell.iter(&wtxn).unwrap() {
+        let (_key, bitmap) = ret.unwrap();
+        assert!(!bitmap.contains(0));
+    }
+    insta::assert_debug_snapshot!(db.in_shape(&wtxn, &old_area).unwrap(), @"RoaringBitmap<[]>");
+    let new_area = polygon![
+        (x: 99.0, y: 39.0),
+        (x: 101.0, y: 39.0),
+        (x: 101.0, y: 41.0),
+        (x: 99.0, y: 41.0),
+    ];
+    insta::assert_debug_snapshot!(db.in_shape(&wtxn, &new_area).unwrap(), @"RoaringBitmap<[1, 2, 3, 4]>");
+    assert_eq!(db.check(&wtxn).unwrap(), vec![]);
+}
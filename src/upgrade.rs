@@ -0,0 +1,57 @@
+use heed::{Env, RwTxn};
+use steppe::Progress;
+
+use crate::{Cellulite, Error, Result, metadata::FORMAT_VERSION};
+
+steppe::make_enum_progress! {
+    pub enum UpgradeSteps {
+        RetrieveVersion,
+        CreateMissingDatabases,
+        UpdateTheMetadata,
+    }
+}
+
+impl Cellulite {
+    /// Upgrade a database written in an older format to the current format, in place.
+    /// Must be called before [`Self::build`] when it returns [`Error::VersionMismatchOnBuild`].
+    /// The version of cellulite stored in the database is updated as well.
+    ///
+    /// The databases introduced by the newer versions are created, the environment must be opened with
+    /// at least [`Self::nb_dbs`] databases and the prefix must be the one used to create the database.
+    ///
+    /// The databases in the format v1 don't store the threshold they were built with, it must be provided.
+    /// It's [`Self::default_threshold`] unless the `threshold` field was changed before building the database.
+    /// It's ignored if the database is already in a newer format.
+    // Every breaking change of the on-disk format must bump the `FORMAT_VERSION` and add its migration here,
+    // from the oldest to the newest format.
+    pub fn upgrade<Tls>(
+        &mut self,
+        env: &Env<Tls>,
+        wtxn: &mut RwTxn,
+        prefix: &str,
+        threshold: u64,
+        progress: &impl Progress,
+    ) -> Result<()> {
+        progress.update(UpgradeSteps::RetrieveVersion);
+        let db_format = self.get_format_version(wtxn)?;
+        if db_format > FORMAT_VERSION {
+            return Err(Error::UpgradeFromNewerVersion(db_format));
+        }
+
+        // v1 -> v2: the properties database and the settings were introduced
+        progress.update(UpgradeSteps::CreateMissingDatabases);
+        if self.properties.is_none() {
+            self.properties =
+                Some(env.create_database(wtxn, Some(&format!("{prefix}-properties")))?);
+        }
+        if db_format < 2 {
+            let settings = self.settings.with_threshold(threshold);
+            settings.validate()?;
+            self.settings = settings;
+        }
+
+        progress.update(UpgradeSteps::UpdateTheMetadata);
+        self.update_metadata(wtxn)?;
+        Ok(())
+    }
+}
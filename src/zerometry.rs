@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 
-use geo::Geometry;
+use geo::{Geometry, GeometryCollection, MultiLineString, MultiPoint, MultiPolygon, Point};
 use heed::BoxedError;
 use zerometry::Zerometry;
 
@@ -24,3 +24,60 @@ impl heed::BytesEncode<'_> for ZerometryCodec {
         Ok(Cow::Owned(bytes))
     }
 }
+
+/// Validate a buffer supposed to contain a `Zerometry` and return the geometry it contains.
+/// The buffer is decoded, converted to a geometry and encoded again, the result must be exactly the same buffer.
+pub(crate) fn validate_zerometry(bytes: &[u8]) -> Result<Geometry, String> {
+    // The coordinates are read directly from the buffer, it must be aligned on 64 bits
+    let mut aligned = vec![0_u64; bytes.len().div_ceil(8)];
+    // Safe because the buffer is large enough and any bit pattern is a valid u64
+    let aligned =
+        unsafe { std::slice::from_raw_parts_mut(aligned.as_mut_ptr().cast::<u8>(), bytes.len()) };
+    aligned.copy_from_slice(bytes);
+    let zerometry = Zerometry::try_from_bytes(aligned).map_err(|err| err.to_string())?;
+    let geometry = to_geometry(zerometry);
+
+    let mut encoded = Vec::with_capacity(bytes.len());
+    Zerometry::write_from_geometry(&mut encoded, &geometry).map_err(|err| err.to_string())?;
+    if encoded != bytes {
+        return Err(String::from("the buffer contains unexpected bytes"));
+    }
+    Ok(geometry)
+}
+
+/// Convert a `Zerometry` back to a `geo::Geometry` so it can be used with the `geo` algorithms.
+pub(crate) fn to_geometry(shape: Zerometry) -> Geometry {
+    match shape {
+        Zerometry::Point(point) => Geometry::Point(Point::new(point.lng(), point.lat())),
+        Zerometry::MultiPoints(multi_points) => Geometry::MultiPoint(MultiPoint::new(
+            multi_points
+                .points()
+                .into_iter()
+                .map(|point| Point::new(point.lng(), point.lat()))
+                .collect(),
+        )),
+        Zerometry::Line(line) => Geometry::LineString(line.to_geo()),
+        Zerometry::MultiLines(multi_lines) => Geometry::MultiLineString(MultiLineString::new(
+            multi_lines
+                .lines()
+                .into_iter()
+                .map(|line| line.to_geo())
+                .collect(),
+        )),
+        Zerometry::Polygon(polygon) => Geometry::Polygon(polygon.to_geo()),
+        Zerometry::MultiPolygon(multi_polygon) => Geometry::MultiPolygon(MultiPolygon::new(
+            multi_polygon
+                .polygons()
+                .into_iter()
+                .map(|polygon| polygon.to_geo())
+                .collect(),
+        )),
+        Zerometry::Collection(collection) => {
+            Geometry::GeometryCollection(GeometryCollection(vec![
+                to_geometry(Zerometry::MultiPoints(collection.points())),
+                to_geometry(Zerometry::MultiLines(collection.lines())),
+                to_geometry(Zerometry::MultiPolygon(collection.polygons())),
+            ]))
+        }
+    }
+}
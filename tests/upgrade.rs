@@ -18,8 +18,10 @@ fn from_0_2_0() {
             .unwrap()
     };
     let mut wtxn = env.write_txn().unwrap();
-    let cellulite = Cellulite::create_from_env(&env, &mut wtxn, "cellulite").unwrap();
+    let mut cellulite = Cellulite::create_from_env(&env, &mut wtxn, "cellulite").unwrap();
     insta::assert_snapshot!(cellulite.get_version(&wtxn).unwrap(), @"0.2.0");
+    // The format version didn't exist yet
+    insta::assert_snapshot!(cellulite.get_format_version(&wtxn).unwrap(), @"1");
 
     // This matches only a subset of the multi-point containing all the trees
     let trees = polygon![
@@ -51,7 +53,30 @@ fn from_0_2_0() {
     let shape = cellulite.item(&wtxn, 2).unwrap().unwrap();
     assert!(shape.to_polygon().is_some());
 
-    // The next lines will break the day we do a DB-breaking, we should call the update function here
+    // My desk is also returned when looking for the items containing it
+    let desk_point = cellulite
+        .item(&wtxn, 1)
+        .unwrap()
+        .unwrap()
+        .to_point()
+        .unwrap();
+    let desk_point = geo::Point::new(desk_point.lng(), desk_point.lat());
+    let ret = cellulite.containing(&wtxn, desk_point).unwrap();
+    insta::assert_debug_snapshot!(ret, @"RoaringBitmap<[1, 2]>");
+
+    // Upgrade the database to the current version before writing to it
+    cellulite
+        .upgrade(
+            &env,
+            &mut wtxn,
+            "cellulite",
+            Cellulite::default_threshold(),
+            &NoProgress,
+        )
+        .unwrap();
+    insta::assert_snapshot!(cellulite.get_version(&wtxn).unwrap(), @"0.2.0");
+    insta::assert_snapshot!(cellulite.get_format_version(&wtxn).unwrap(), @"2");
+
     cellulite.delete(&mut wtxn, 2).unwrap();
     cellulite
         .add(